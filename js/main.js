@@ -1,4 +1,4 @@
-import init, { draw } from '../pkg/ray_tracing_wasm.js';
+import init, { draw, add_file, clear_files } from '../pkg/ray_tracing_wasm.js';
 
 const console_output = document.getElementById('console');
 const console_error = console.error;
@@ -17,15 +17,42 @@ const img_width_input = document.getElementById("img-width");
 const img_height_input = document.getElementById("img-height");
 const samples_per_pixel_input = document.getElementById("samples-per-pixel");
 const max_bounces_input = document.getElementById("max-bounces");
+const integrator_input = document.getElementById("integrator");
 const download_btn = document.getElementById("download");
+const files_input = document.getElementById("scene-files");
 
 scene_input.addEventListener("change", async function(e){
 	const scene_json = await (await fetch(e.target.value)).json();
+	await add_scene_files(e.target.value, scene_json);
 	text_input.value = JSON.stringify(scene_json, null, "  ");
 	
 	render_btn.click();
 });
 
+// Scenes refer to picked files by name, e.g. "filename": "grid.png".
+files_input.addEventListener("change", async function(e){
+	clear_files();
+	for(const file of e.target.files){
+		add_file(file.name, new Uint8Array(await file.arrayBuffer()));
+	}
+	
+	render_btn.click();
+});
+
+// Fetches the images and meshes an example scene refers to, relative to the scene file.
+async function add_scene_files(scene_url, scene_json){
+	const names = [];
+	(function find_files(value){
+		if(typeof value !== "object" || value === null) return;
+		if((value.type === "Image" || value.type === "Mesh") && typeof value.filename === "string") names.push(value.filename);
+		Object.values(value).forEach(find_files);
+	})(scene_json);
+	for(const name of names){
+		const response = await fetch(new URL(name, new URL(scene_url, document.baseURI)));
+		if(response.ok) add_file(name, new Uint8Array(await response.arrayBuffer()));
+	}
+}
+
 render_btn.addEventListener('click', () => {
 	if(!img_width_input.reportValidity() || Number.isNaN(img_width_input.valueAsNumber)) throw new Error("Image width is invalid!");
 	if(!img_height_input.reportValidity() || Number.isNaN(img_height_input.valueAsNumber)) throw new Error("Image height is invalid!");
@@ -36,7 +63,13 @@ render_btn.addEventListener('click', () => {
 	canvas.height = img_height_input.valueAsNumber;
 	
 	const ctx = canvas.getContext('2d');
-	draw(ctx, text_input.value, img_width_input.valueAsNumber, img_height_input.valueAsNumber, samples_per_pixel_input.valueAsNumber, max_bounces_input.valueAsNumber);
+	try {
+		draw(ctx, text_input.value, img_width_input.valueAsNumber, img_height_input.valueAsNumber, samples_per_pixel_input.valueAsNumber, max_bounces_input.valueAsNumber, integrator_input.value);
+	} catch (e) {
+		console.error(e.message);
+		return;
+	}
+	console_output.value = "";
 	download_btn.href = canvas.toDataURL('image/png').replace(/^data:image\/[^;]*/, 'data:application/octet-stream');
 });
 
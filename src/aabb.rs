@@ -0,0 +1,96 @@
+use cgmath::{Point3, Vector3, Matrix4};
+use cgmath::{Transform, EuclideanSpace};
+use crate::Ray;
+
+/// Axis aligned bounding box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+	pub min: Point3<f64>,
+	pub max: Point3<f64>,
+}
+
+pub trait Bounded {
+	/// World space bounds, or `None` for primitives with infinite extent.
+	fn bounds(&self) -> Option<Aabb>;
+}
+
+impl Aabb {
+	pub fn new(min: Point3<f64>, max: Point3<f64>) -> Aabb {
+		Aabb { min, max }
+	}
+
+	/// An inverted box that contains nothing and is the identity for `union`.
+	pub fn empty() -> Aabb {
+		Aabb {
+			min: Point3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+			max: Point3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+		}
+	}
+
+	pub fn from_points<I: IntoIterator<Item = Point3<f64>>>(points: I) -> Aabb {
+		points.into_iter().fold(Aabb::empty(), |bounds, point| bounds.grow(point))
+	}
+
+	pub fn grow(&self, point: Point3<f64>) -> Aabb {
+		Aabb {
+			min: Point3::new(self.min.x.min(point.x), self.min.y.min(point.y), self.min.z.min(point.z)),
+			max: Point3::new(self.max.x.max(point.x), self.max.y.max(point.y), self.max.z.max(point.z)),
+		}
+	}
+
+	pub fn union(&self, other: &Aabb) -> Aabb {
+		self.grow(other.min).grow(other.max)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+	}
+
+	pub fn extent(&self) -> Vector3<f64> {
+		self.max - self.min
+	}
+
+	pub fn centroid(&self) -> Point3<f64> {
+		self.min.midpoint(self.max)
+	}
+
+	pub fn surface_area(&self) -> f64 {
+		if self.is_empty() { return 0.0; }
+		let d = self.extent();
+		2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+	}
+
+	/// Index of the axis along which the box is longest.
+	pub fn longest_axis(&self) -> usize {
+		let d = self.extent();
+		if d.x >= d.y && d.x >= d.z { 0 } else if d.y >= d.z { 1 } else { 2 }
+	}
+
+	/// Bounds of this box after an affine transform, found by transforming all eight corners.
+	pub fn transform(&self, transform: &Matrix4<f64>) -> Aabb {
+		Aabb::from_points((0..8).map(|i| {
+			let corner = Point3::new(
+				if i & 1 == 0 { self.min.x } else { self.max.x },
+				if i & 2 == 0 { self.min.y } else { self.max.y },
+				if i & 4 == 0 { self.min.z } else { self.max.z },
+			);
+			transform.transform_point(corner)
+		}))
+	}
+
+	/// Slab test against `ray` restricted to `[0, t_max]`, returning the entry distance.
+	/// `inv_direction` is the componentwise reciprocal of `ray.direction`.
+	pub fn intersect(&self, ray: &Ray, inv_direction: Vector3<f64>, t_max: f64) -> Option<f64> {
+		let mut t0 = 0.0_f64;
+		let mut t1 = t_max;
+		for axis in 0..3 {
+			let near = (self.min[axis] - ray.origin[axis]) * inv_direction[axis];
+			let far = (self.max[axis] - ray.origin[axis]) * inv_direction[axis];
+			let (near, far) = if near <= far { (near, far) } else { (far, near) };
+			t0 = t0.max(near);
+			t1 = t1.min(far);
+			if t0 > t1 { return None; }
+		}
+		Some(t0)
+	}
+}
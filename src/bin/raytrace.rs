@@ -0,0 +1,181 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use ray_tracing_wasm::{render, RenderSettings, Image, Scene, Object};
+
+const USAGE: &str = "\
+Usage: raytrace <scene.json> [options]
+
+Options:
+  -o, --output <file>       Output image path (default: out.png)
+  -f, --format <format>     Output format: png, ppm or pfm (default: from output extension)
+  -w, --width <pixels>      Image width (default: 512)
+  -h, --height <pixels>     Image height (default: 512)
+  -s, --spp <samples>       Samples per pixel (default: 1)
+  -b, --bounces <count>     Maximum reflection bounces (default: 1)
+  -i, --integrator <name>   Integrator: whitted or path (default: whitted)
+      --seed <seed>         Random seed for sample jitter (default: 0)
+      --tile-size <pixels>  Size of the square render tiles (default: 32)
+      --threads <count>     Worker threads, 0 uses every core (default: 0)
+      --stats               Print mesh BVH statistics before rendering
+      --help                Print this message
+";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+	Png,
+	Ppm,
+	Pfm,
+}
+
+impl OutputFormat {
+	fn parse(name: &str) -> Option<OutputFormat> {
+		match name.to_ascii_lowercase().as_str() {
+			"png" => Some(OutputFormat::Png),
+			"ppm" => Some(OutputFormat::Ppm),
+			"pfm" => Some(OutputFormat::Pfm),
+			_ => None,
+		}
+	}
+}
+
+struct Args {
+	scene: PathBuf,
+	output: PathBuf,
+	format: Option<OutputFormat>,
+	settings: RenderSettings,
+	stats: bool,
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+	let value = value.ok_or_else(|| format!("missing value for {}", flag))?;
+	value.parse().map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
+
+fn parse_args() -> Result<Args, String> {
+	let mut scene = None;
+	let mut output = PathBuf::from("out.png");
+	let mut format = None;
+	let mut settings = RenderSettings::default();
+	let mut stats = false;
+
+	let mut args = std::env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"-o" | "--output" => output = parse_value(&arg, args.next())?,
+			"-f" | "--format" => {
+				let name: String = parse_value(&arg, args.next())?;
+				format = Some(OutputFormat::parse(&name).ok_or_else(|| format!("unknown output format: {}", name))?);
+			}
+			"-w" | "--width" => settings.width = parse_value(&arg, args.next())?,
+			"-h" | "--height" => settings.height = parse_value(&arg, args.next())?,
+			"-s" | "--spp" => settings.num_samples = parse_value(&arg, args.next())?,
+			"-b" | "--bounces" => settings.max_bounces = parse_value(&arg, args.next())?,
+			"-i" | "--integrator" => {
+				let name: String = parse_value(&arg, args.next())?;
+				settings.integrator = name.parse()?;
+			}
+			"--seed" => settings.seed = parse_value(&arg, args.next())?,
+			"--tile-size" => settings.tile_size = parse_value(&arg, args.next())?,
+			"--threads" => settings.threads = parse_value(&arg, args.next())?,
+			"--stats" => stats = true,
+			"--help" => {
+				print!("{}", USAGE);
+				process::exit(0);
+			}
+			_ if arg.starts_with('-') => return Err(format!("unknown option: {}", arg)),
+			_ if scene.is_none() => scene = Some(PathBuf::from(arg)),
+			_ => return Err(format!("unexpected argument: {}", arg)),
+		}
+	}
+
+	if settings.width == 0 || settings.height == 0 {
+		return Err(String::from("width and height must be at least 1 pixel"));
+	}
+	if settings.num_samples == 0 {
+		return Err(String::from("samples per pixel must be at least 1"));
+	}
+	Ok(Args {
+		scene: scene.ok_or("missing scene file")?,
+		output,
+		format,
+		settings,
+		stats,
+	})
+}
+
+fn write_ppm(image: &Image, path: &Path) -> Result<(), Box<dyn Error>> {
+	let mut file = BufWriter::new(File::create(path)?);
+	write!(file, "P6\n{} {}\n255\n", image.width, image.height)?;
+	for pixel in image.to_rgba8().chunks_exact(4) {
+		file.write_all(&pixel[..3])?;
+	}
+	file.flush()?;
+	Ok(())
+}
+
+/// Writes the unclamped linear colors as a little-endian PFM, whose rows run bottom to top.
+fn write_pfm(image: &Image, path: &Path) -> Result<(), Box<dyn Error>> {
+	let mut file = BufWriter::new(File::create(path)?);
+	write!(file, "PF\n{} {}\n-1.0\n", image.width, image.height)?;
+	for y in (0..image.height).rev() {
+		for x in 0..image.width {
+			let pixel = image.get(x, y);
+			for v in [pixel.x, pixel.y, pixel.z] {
+				file.write_all(&(v as f32).to_le_bytes())?;
+			}
+		}
+	}
+	file.flush()?;
+	Ok(())
+}
+
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
+	let format = match args.format {
+		Some(format) => format,
+		None => args.output.extension()
+			.and_then(|extension| extension.to_str())
+			.and_then(OutputFormat::parse)
+			.ok_or("cannot infer output format from extension, use --format")?,
+	};
+
+	let json = std::fs::read_to_string(&args.scene)?;
+	let base_dir = args.scene.parent().unwrap_or(Path::new(""));
+	let scene = Scene::from_json_with_base_dir(&json, base_dir)?;
+
+	if args.stats {
+		for (i, object) in scene.objects.iter().enumerate() {
+			if let Object::Mesh(mesh) = object {
+				let stats = mesh.bvh_stats();
+				eprintln!(
+					"mesh {}: {} triangles, {} nodes, {} leaves, depth {}, leaf size {:.2} avg / {} max",
+					i, mesh.vertices.len() / 3, stats.node_count, stats.leaf_count, stats.max_depth, stats.average_leaf_size, stats.max_leaf_size,
+				);
+			}
+		}
+	}
+
+	let image = render(&scene, &args.settings);
+	match format {
+		OutputFormat::Png => image::save_buffer(&args.output, &image.to_rgba8(), image.width, image.height, image::ExtendedColorType::Rgba8)?,
+		OutputFormat::Ppm => write_ppm(&image, &args.output)?,
+		OutputFormat::Pfm => write_pfm(&image, &args.output)?,
+	}
+	Ok(())
+}
+
+fn main() {
+	let args = match parse_args() {
+		Ok(args) => args,
+		Err(message) => {
+			eprintln!("error: {}\n\n{}", message, USAGE);
+			process::exit(2);
+		}
+	};
+	if let Err(error) = run(args) {
+		eprintln!("error: {}", error);
+		process::exit(1);
+	}
+}
@@ -0,0 +1,29 @@
+use cgmath::{Vector2, Vector3};
+use cgmath::InnerSpace;
+use crate::{Hit, Texture};
+use crate::sampling::luminance;
+
+/// Shading normal from a tangent space normal map at `hit`.
+pub(crate) fn normal_map(hit: &Hit, map: &Texture, strength: f64, footprint: f64) -> Vector3<f64> {
+	let color = map.evaluate(hit.uv, hit.position, footprint) * 2.0 - Vector3::new(1.0, 1.0, 1.0);
+	let normal = hit.tangent * (color.x * strength) + hit.bitangent * (color.y * strength) + hit.normal.normalize() * color.z;
+	if normal.magnitude2() > 1e-12 { normal.normalize() } else { hit.normal }
+}
+
+/// Shading normal from a height map at `hit`, with slopes found by finite
+/// differences one footprint apart.
+pub(crate) fn bump_map(hit: &Hit, map: &Texture, depth: f64, footprint: f64) -> Vector3<f64> {
+	if hit.uv_density <= 0.0 { return hit.normal; }
+	let delta = footprint.max(1e-3);
+	// Steps along the surface for solid textures, matching the steps in uv.
+	let height = |du: f64, dv: f64| {
+		let position = hit.position + (hit.tangent * du + hit.bitangent * dv) / hit.uv_density;
+		luminance(map.evaluate(hit.uv + Vector2::new(du, dv), position, footprint))
+	};
+	let center = height(0.0, 0.0);
+	let slope_u = (height(delta, 0.0) - center) / delta;
+	let slope_v = (height(0.0, delta) - center) / delta;
+	// Heights are in map units per uv, the normal tilts by their world space slope.
+	let normal = hit.normal.normalize() - (hit.tangent * slope_u + hit.bitangent * slope_v) * (depth * hit.uv_density);
+	normal.normalize()
+}
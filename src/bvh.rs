@@ -0,0 +1,247 @@
+use cgmath::Vector3;
+use crate::{Ray, Hit, Aabb};
+
+const MAX_LEAF_SIZE: usize = 8;
+const SAH_BINS: usize = 12;
+/// Cost of visiting an interior node relative to intersecting one item.
+const TRAVERSAL_COST: f64 = 1.0;
+
+/// Shape of a built tree, useful when tuning the build parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BvhStats {
+	pub node_count: usize,
+	pub leaf_count: usize,
+	pub max_depth: usize,
+	pub max_leaf_size: usize,
+	pub average_leaf_size: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SahBin {
+	bounds: Aabb,
+	count: usize,
+}
+
+fn sah_bin(bounds: &Aabb, centroid_bounds: &Aabb, axis: usize) -> usize {
+	let offset = (bounds.centroid()[axis] - centroid_bounds.min[axis]) / centroid_bounds.extent()[axis];
+	((offset * SAH_BINS as f64) as usize).min(SAH_BINS - 1)
+}
+
+#[derive(Debug, Clone)]
+struct BvhNode {
+	bounds: Aabb,
+	/// First item for leaves, index of the second child for interior nodes.
+	/// The first child of an interior node always directly follows it.
+	offset: usize,
+	/// Number of items in a leaf, zero for interior nodes.
+	count: usize,
+	axis: usize,
+}
+
+/// Bounding volume hierarchy over a list of items, built with binned surface
+/// area heuristic splits and flattened into a depth-first node array. Items are
+/// referred to by their index in the slice of bounds the tree was built from.
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+	nodes: Vec<BvhNode>,
+	indices: Vec<usize>,
+}
+
+impl Bvh {
+	pub fn new(bounds: &[Aabb]) -> Bvh {
+		let mut bvh = Bvh {
+			nodes: Vec::with_capacity(2 * bounds.len()),
+			indices: (0..bounds.len()).collect(),
+		};
+		if !bounds.is_empty() {
+			bvh.build(bounds, 0, bounds.len());
+		}
+		bvh
+	}
+
+	fn build(&mut self, bounds: &[Aabb], start: usize, end: usize) -> usize {
+		let node_bounds = self.indices[start..end].iter().fold(Aabb::empty(), |acc, &i| acc.union(&bounds[i]));
+		let node = self.nodes.len();
+		self.nodes.push(BvhNode {
+			bounds: node_bounds,
+			offset: start,
+			count: end - start,
+			axis: 0,
+		});
+		if end - start == 1 { return node; }
+
+		let centroid_bounds = self.indices[start..end].iter().fold(Aabb::empty(), |acc, &i| acc.grow(bounds[i].centroid()));
+		let (axis, mid) = match self.sah_split(bounds, start, end, &node_bounds, &centroid_bounds) {
+			Some(split) => split,
+			None if end - start <= MAX_LEAF_SIZE => return node,
+			None => {
+				// SAH found nothing better than a leaf, but the leaf would be too big.
+				let axis = centroid_bounds.longest_axis();
+				if centroid_bounds.extent()[axis] <= 0.0 { return node; }
+				let mid = (start + end) / 2;
+				self.indices[start..end].select_nth_unstable_by(mid - start, |&a, &b| {
+					bounds[a].centroid()[axis].total_cmp(&bounds[b].centroid()[axis])
+				});
+				(axis, mid)
+			}
+		};
+
+		self.build(bounds, start, mid);
+		let second = self.build(bounds, mid, end);
+		self.nodes[node] = BvhNode {
+			bounds: node_bounds,
+			offset: second,
+			count: 0,
+			axis,
+		};
+		node
+	}
+
+	/// Bins the item centroids along every axis and partitions the items at the
+	/// bin boundary with the lowest surface area heuristic cost. Returns the split
+	/// axis and the partition point, or `None` if a leaf is cheaper.
+	fn sah_split(&mut self, bounds: &[Aabb], start: usize, end: usize, node_bounds: &Aabb, centroid_bounds: &Aabb) -> Option<(usize, usize)> {
+		let mut best: Option<(f64, usize, usize)> = None;
+		for axis in 0..3 {
+			if centroid_bounds.extent()[axis] <= 0.0 { continue; }
+
+			let mut bins = [SahBin { bounds: Aabb::empty(), count: 0 }; SAH_BINS];
+			for &i in &self.indices[start..end] {
+				let bin = &mut bins[sah_bin(&bounds[i], centroid_bounds, axis)];
+				bin.bounds = bin.bounds.union(&bounds[i]);
+				bin.count += 1;
+			}
+
+			// Sweep from the right to get the cost of every right hand side.
+			let mut right_area = [0.0; SAH_BINS];
+			let mut right_count = [0; SAH_BINS];
+			let mut acc = SahBin { bounds: Aabb::empty(), count: 0 };
+			for split in (1..SAH_BINS).rev() {
+				acc.bounds = acc.bounds.union(&bins[split].bounds);
+				acc.count += bins[split].count;
+				right_area[split] = acc.bounds.surface_area();
+				right_count[split] = acc.count;
+			}
+
+			let mut acc = SahBin { bounds: Aabb::empty(), count: 0 };
+			for split in 1..SAH_BINS {
+				acc.bounds = acc.bounds.union(&bins[split - 1].bounds);
+				acc.count += bins[split - 1].count;
+				if acc.count == 0 || right_count[split] == 0 { continue; }
+				let cost = acc.bounds.surface_area() * acc.count as f64 + right_area[split] * right_count[split] as f64;
+				if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+					best = Some((cost, axis, split));
+				}
+			}
+		}
+
+		let (cost, axis, split) = best?;
+		let count = end - start;
+		let cost = TRAVERSAL_COST + cost / node_bounds.surface_area().max(f64::MIN_POSITIVE);
+		if cost >= count as f64 && count <= MAX_LEAF_SIZE { return None; }
+
+		let (left, right): (Vec<usize>, Vec<usize>) = self.indices[start..end].iter().partition(|&&i| {
+			sah_bin(&bounds[i], centroid_bounds, axis) < split
+		});
+		let mid = start + left.len();
+		self.indices[start..mid].copy_from_slice(&left);
+		self.indices[mid..end].copy_from_slice(&right);
+		Some((axis, mid))
+	}
+
+	pub fn stats(&self) -> BvhStats {
+		let mut stats = BvhStats::default();
+		if self.nodes.is_empty() { return stats; }
+		let mut stack = vec![(0, 0)];
+		while let Some((node, depth)) = stack.pop() {
+			let BvhNode { offset, count, .. } = self.nodes[node];
+			stats.node_count += 1;
+			stats.max_depth = stats.max_depth.max(depth);
+			if count > 0 {
+				stats.leaf_count += 1;
+				stats.max_leaf_size = stats.max_leaf_size.max(count);
+			} else {
+				stack.push((node + 1, depth + 1));
+				stack.push((offset, depth + 1));
+			}
+		}
+		stats.average_leaf_size = self.indices.len() as f64 / stats.leaf_count as f64;
+		stats
+	}
+
+	/// Finds the closest hit along `ray`, calling `intersect` with the index of
+	/// every item whose bounds the ray passes through.
+	pub fn intersect<F>(&self, ray: &Ray, mut intersect: F) -> Option<Hit>
+	where
+		F: FnMut(usize) -> Option<Hit>,
+	{
+		if self.nodes.is_empty() { return None; }
+		let inv_direction = Vector3::new(1.0 / ray.direction.x, 1.0 / ray.direction.y, 1.0 / ray.direction.z);
+
+		let mut closest: Option<Hit> = None;
+		let mut stack = vec![0];
+		while let Some(node) = stack.pop() {
+			let t_max = closest.as_ref().map_or(f64::INFINITY, |hit| hit.distance);
+			let BvhNode { ref bounds, offset, count, axis } = self.nodes[node];
+			if bounds.intersect(ray, inv_direction, t_max).is_none() { continue; }
+
+			if count > 0 {
+				for &i in &self.indices[offset..offset + count] {
+					if let Some(hit) = intersect(i) {
+						if closest.as_ref().is_none_or(|closest| hit < *closest) {
+							closest = Some(hit);
+						}
+					}
+				}
+			} else if ray.direction[axis] < 0.0 {
+				stack.push(node + 1);
+				stack.push(offset);
+			} else {
+				stack.push(offset);
+				stack.push(node + 1);
+			}
+		}
+		closest
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use cgmath::{Point3, Vector3, EuclideanSpace};
+	use rand::{Rng, SeedableRng, rngs::StdRng};
+	use std::sync::Arc;
+	use crate::{Intersect, Object, Objects, Sphere, Plane, Material, Keyframes};
+	use crate::testing::{random_vector, random_ray};
+
+	#[test]
+	fn objects_match_brute_force() {
+		let mut rng = StdRng::seed_from_u64(1);
+		let material = Arc::new(Material::DebugNormals);
+		let mut objects: Vec<Object> = (0..200).map(|_| Object::Sphere(Sphere {
+			origin: Keyframes::constant(Point3::from_vec(random_vector(&mut rng, 10.0))),
+			radius: rng.gen_range(0.1..1.5),
+			material: material.clone(),
+		})).collect();
+		objects.insert(50, Object::Plane(Plane {
+			origin: Point3::new(0.0, -8.0, 0.0),
+			normal: Vector3::new(0.0, 1.0, 0.0),
+			tile_size: 1.0,
+			material: material.clone(),
+		}));
+		let scene_objects = Objects::new(objects.clone());
+		// Objects keep their order, so indices match the scene's `objects`.
+		assert!(matches!(scene_objects.iter().nth(50), Some(Object::Plane(_))));
+
+		let mut hits = 0;
+		for _ in 0..2000 {
+			let ray = random_ray(&mut rng, 12.0);
+			let expected = objects.iter().filter_map(|object| object.intersect(&ray)).min();
+			let actual = scene_objects.intersect(&ray);
+			assert_eq!(expected.as_ref().map(|hit| hit.distance), actual.as_ref().map(|hit| hit.distance));
+			assert_eq!(expected.as_ref().map(|hit| hit.position), actual.map(|hit| hit.position));
+			hits += expected.is_some() as usize;
+		}
+		// Most rays should hit something, or the comparison proves little.
+		assert!(hits > 1000, "only {} rays hit", hits);
+	}
+}
@@ -1,66 +1,347 @@
 use serde::{Serialize, Deserialize};
-use cgmath::{Point3, Vector3, Matrix4};
-use crate::{Ray, VectorFormat};
+use cgmath::{Point3, Vector3, Matrix3};
+use cgmath::{InnerSpace, EuclideanSpace, SquareMatrix};
+use rand::Rng;
+use crate::{Ray, VectorFormat, Object, Bounded, SceneError};
+use crate::sampling::{uniform_disk, uniform_polygon, orthonormal_basis};
+use std::f64::consts::PI;
 
+/// How a fisheye lens maps angles from the view direction to distances from
+/// the image center.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum FisheyeMapping {
+	/// Distance proportional to the angle.
+	#[default]
+	Equidistant,
+	/// Equal areas on the image cover equal solid angles.
+	Equisolid,
+}
+
+/// How directions in front of the camera map to the image.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Projection {
+	/// Pinhole projection covering the camera's `fovy` vertically.
+	#[default]
+	Perspective,
+	/// Parallel rays covering `height` world units vertically.
+	Orthographic {
+		height: f64,
+	},
+	/// Circular image touching the top and bottom of the frame, covering `fov`
+	/// radians across its diameter.
+	Fisheye {
+		fov: f64,
+		#[serde(default)]
+		mapping: FisheyeMapping,
+	},
+	/// Full 360 by 180 degree panorama, best rendered twice as wide as high,
+	/// with the view direction in the center.
+	Equirectangular,
+}
+
+/// A thin lens camera. With a zero `aperture` it is a pinhole camera and
+/// everything is in focus, otherwise rays start on the lens and only the plane
+/// `focus_distance` in front of it is sharp (a sphere for the fisheye and
+/// panorama projections).
+///
+/// Call `update_view` after changing `direction`, `up` or `roll`.
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(from = "CameraFormat", into = "CameraFormat")]
+#[serde(try_from = "CameraFormat", into = "CameraFormat")]
 pub struct Camera {
 	pub origin: Point3<f64>,
 	pub direction: Vector3<f64>,
+	/// Roughly which way is up in the image. Any direction not parallel to
+	/// `direction` works, and a parallel one is replaced by a perpendicular one.
+	pub up: Vector3<f64>,
+	/// Angle in radians the camera is turned counterclockwise around `direction`.
+	pub roll: f64,
 	pub fovy: f64,
 	pub aspect: f64,
+	pub projection: Projection,
+	/// Diameter of the lens in world units.
+	pub aperture: f64,
+	/// Distance along `direction` to the plane in focus.
+	pub focus_distance: f64,
+	/// Number of aperture blades, which gives out of focus highlights that shape.
+	/// Fewer than three means a round aperture.
+	pub blades: u32,
+	/// Rotation of the aperture polygon in radians.
+	pub blade_rotation: f64,
+	/// Time the shutter opens. Rays are spread evenly over the time it is open,
+	/// blurring objects that move meanwhile.
+	pub shutter_open: f64,
+	pub shutter_close: f64,
+	/// Camera to world rotation, with columns right, up and backward.
+	to_world: Matrix3<f64>,
 }
 
 impl Camera {
-	pub fn get_ray(&self, u: f64, v: f64) -> Ray {
-		let h: f64 = (self.fovy / 2.0).tan();
-		let viewport_height: f64 = 2.0 * h;
-		let viewport_width: f64 = self.aspect * viewport_height;
-		
-		let target = self.origin + self.direction;
-		let up = Vector3::new(0.0, 1.0, 0.0);
-		let view = Matrix4::look_at_rh(self.origin, target, up);
-		
-		let horizontal = Vector3::new(viewport_width, 0.0, 0.0);
-		let vertical = Vector3::new(0.0, viewport_height, 0.0);
-		let lower_left_corner = Vector3::new(0.0, 0.0, -1.0) - (horizontal/2.0) - (vertical/2.0);
-		
-		let direction = lower_left_corner + u*horizontal + v*vertical;
-		let direction = (view * direction.extend(0.0)).truncate();
+	/// Recomputes the view basis from `direction`, `up` and `roll`.
+	pub fn update_view(&mut self) {
+		let forward = self.direction.normalize();
+		let mut right = forward.cross(self.up);
+		if right.magnitude2() < 1e-12 {
+			// Looking along `up`, so any perpendicular works as well as another.
+			right = orthonormal_basis(forward).0;
+		}
+		let right = right.normalize();
+		let up = right.cross(forward);
+		let (sin, cos) = self.roll.sin_cos();
+		self.to_world = Matrix3::from_cols(right * cos + up * sin, up * cos - right * sin, -forward);
+	}
+
+	/// Camera space origin and direction of the pinhole ray through `(u, v)`,
+	/// looking down -z. Planar projections return directions reaching z = -1.
+	fn project(&self, u: f64, v: f64) -> Option<(Vector3<f64>, Vector3<f64>)> {
+		let zero = Vector3::new(0.0, 0.0, 0.0);
+		match self.projection {
+			Projection::Perspective => {
+				let h: f64 = (self.fovy / 2.0).tan();
+				let viewport_height: f64 = 2.0 * h;
+				let viewport_width: f64 = self.aspect * viewport_height;
+				
+				let horizontal = Vector3::new(viewport_width, 0.0, 0.0);
+				let vertical = Vector3::new(0.0, viewport_height, 0.0);
+				let lower_left_corner = Vector3::new(0.0, 0.0, -1.0) - (horizontal/2.0) - (vertical/2.0);
+				
+				Some((zero, lower_left_corner + u*horizontal + v*vertical))
+			}
+			Projection::Orthographic { height } => {
+				let offset = Vector3::new((u - 0.5) * height * self.aspect, (v - 0.5) * height, 0.0);
+				Some((offset, Vector3::new(0.0, 0.0, -1.0)))
+			}
+			Projection::Fisheye { fov, mapping } => {
+				let (x, y) = ((2.0 * u - 1.0) * self.aspect, 2.0 * v - 1.0);
+				let r = (x * x + y * y).sqrt();
+				if r > 1.0 { return None; }
+				let theta = match mapping {
+					FisheyeMapping::Equidistant => r * fov / 2.0,
+					FisheyeMapping::Equisolid => 2.0 * (r * (fov / 4.0).sin()).clamp(-1.0, 1.0).asin(),
+				};
+				let phi = y.atan2(x);
+				Some((zero, Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), -theta.cos())))
+			}
+			Projection::Equirectangular => {
+				let phi = (u - 0.5) * 2.0 * PI;
+				let theta = (v - 0.5) * PI;
+				Some((zero, Vector3::new(theta.cos() * phi.sin(), theta.sin(), -theta.cos() * phi.cos())))
+			}
+		}
+	}
+
+	/// Ray through `(u, v)` on the image, where `(0, 0)` is the bottom left
+	/// corner, or `None` outside the circle of a fisheye image. Only cameras
+	/// with an aperture or an open shutter draw from `rng`.
+	pub fn get_ray<R: Rng>(&self, u: f64, v: f64, rng: &mut R) -> Option<Ray> {
+		let (offset, direction) = self.project(u, v)?;
+		let (offset, direction) = if self.aperture > 0.0 {
+			// Aim from a point on the lens at where the pinhole ray meets the focus plane.
+			let (x, y) = if self.blades >= 3 { uniform_polygon(self.blades, self.blade_rotation, rng) } else { uniform_disk(rng) };
+			let lens = offset + Vector3::new(x, y, 0.0) * (self.aperture / 2.0);
+			(lens, offset + direction * self.focus_distance - lens)
+		} else {
+			(offset, direction)
+		};
+		let time = if self.shutter_close > self.shutter_open {
+			rng.gen_range(self.shutter_open..self.shutter_close)
+		} else {
+			self.shutter_open
+		};
 		
-		Ray {
-			origin: self.origin,
-			direction,
+		Some(Ray {
+			origin: self.origin + self.to_world * offset,
+			direction: (self.to_world * direction).normalize(),
+			spread: 0.0,
+			time,
+		})
+	}
+
+	/// Angle between the rays of neighbouring pixels at the image center, for an
+	/// image `height` pixels high. Zero for orthographic cameras, whose rays
+	/// don't spread.
+	pub fn pixel_spread(&self, height: u32) -> f64 {
+		let height = height as f64;
+		match self.projection {
+			Projection::Perspective => self.fovy / height,
+			Projection::Orthographic { .. } => 0.0,
+			Projection::Fisheye { fov, .. } => fov / height,
+			Projection::Equirectangular => PI / height,
 		}
 	}
+
+	/// Distance along the view direction to `point`, if it is in front of the camera.
+	fn focus_depth(&self, point: Point3<f64>) -> Option<f64> {
+		let depth = cgmath::dot(point - self.origin, self.direction.normalize());
+		if depth > 0.0 { Some(depth) } else { None }
+	}
+}
+
+/// What to focus on, a point or the center of the object at an index in the
+/// scene's `objects`.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FocusFormat {
+	Point(VectorFormat),
+	Object(usize),
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct CameraFormat {
 	pub origin: VectorFormat,
-	pub direction: VectorFormat,
+	/// Defaults to looking down -z.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub direction: Option<VectorFormat>,
+	/// Point to look at, overrides `direction`.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub look_at: Option<VectorFormat>,
+	/// Defaults to +y.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub up: Option<VectorFormat>,
+	#[serde(default, skip_serializing_if = "is_default")]
+	pub roll: f64,
+	/// Only used by the perspective projection.
 	pub fovy: f64,
 	// pub aspect: f64,
+	#[serde(default, skip_serializing_if = "is_default")]
+	pub projection: Projection,
+	#[serde(default, skip_serializing_if = "is_default")]
+	pub aperture: f64,
+	#[serde(default = "default_focus_distance")]
+	pub focus_distance: f64,
+	/// Overrides `focus_distance`.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub focus_on: Option<FocusFormat>,
+	#[serde(default, skip_serializing_if = "is_default")]
+	pub blades: u32,
+	#[serde(default, skip_serializing_if = "is_default")]
+	pub blade_rotation: f64,
+	#[serde(default, skip_serializing_if = "is_default")]
+	pub shutter_open: f64,
+	/// Defaults to `shutter_open`, an instantaneous exposure.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub shutter_close: Option<f64>,
 }
 
-impl From<CameraFormat> for Camera {
-    fn from(v: CameraFormat) -> Camera { 
-        Camera {
-            origin: v.origin.into(),
-			direction: v.direction.into(),
-            fovy: v.fovy,
+fn is_default<T: Default + PartialEq>(v: &T) -> bool {
+	*v == T::default()
+}
+
+fn default_focus_distance() -> f64 {
+	1.0
+}
+
+impl CameraFormat {
+	/// Builds the camera, focusing on one of `objects` if `focus_on` names one.
+	pub fn load(self, objects: &[Object]) -> Result<Camera, SceneError> {
+		let origin: Point3<f64> = self.origin.into();
+		let looks_at = self.look_at.is_some();
+		let direction = match (self.look_at, self.direction) {
+			(Some(target), _) => Point3::from(target) - origin,
+			(None, Some(direction)) => direction.into(),
+			(None, None) => Vector3::new(0.0, 0.0, -1.0),
+		};
+		if direction.magnitude2() < 1e-12 {
+			let message = if looks_at { "look_at is the camera's origin" } else { "direction is zero" };
+			return Err(SceneError::InvalidCamera { message: String::from(message) });
+		}
+		let mut camera = Camera {
+			origin,
+			direction,
+			up: self.up.map_or(Vector3::new(0.0, 1.0, 0.0), |up| up.into()),
+			roll: self.roll,
+			fovy: self.fovy,
 			aspect: 1.0,
-        }
-    }
+			projection: self.projection,
+			aperture: self.aperture,
+			focus_distance: self.focus_distance,
+			blades: self.blades,
+			blade_rotation: self.blade_rotation,
+			shutter_open: self.shutter_open,
+			shutter_close: self.shutter_close.unwrap_or(self.shutter_open),
+			to_world: Matrix3::identity(),
+		};
+		camera.update_view();
+		match self.focus_on {
+			Some(FocusFormat::Point(point)) => {
+				let invalid = || SceneError::InvalidFocus { index: None, message: String::from("the point is behind the camera") };
+				camera.focus_distance = camera.focus_depth(point.into()).ok_or_else(invalid)?;
+			}
+			Some(FocusFormat::Object(index)) => {
+				let invalid = |message: &str| SceneError::InvalidFocus { index: Some(index), message: String::from(message) };
+				let object = objects.get(index).ok_or_else(|| invalid("there is no such object"))?;
+				let bounds = object.bounds().ok_or_else(|| invalid("the object is unbounded"))?;
+				let center = bounds.min.midpoint(bounds.max);
+				camera.focus_distance = camera.focus_depth(center).ok_or_else(|| invalid("the object is behind the camera"))?;
+			}
+			None => {}
+		}
+		Ok(camera)
+	}
+}
+
+impl TryFrom<CameraFormat> for Camera {
+	type Error = SceneError;
+
+	fn try_from(v: CameraFormat) -> Result<Camera, SceneError> {
+		v.load(&[])
+	}
 }
 
 impl From<Camera> for CameraFormat {
     fn from(v: Camera) -> CameraFormat { 
         CameraFormat {
 			origin: v.origin.into(),
-			direction: v.direction.into(),
+			direction: Some(v.direction.into()),
+			look_at: None,
+			up: Some(v.up.into()),
+			roll: v.roll,
             fovy: v.fovy,
 			// aspect: v.aspect,
+			projection: v.projection,
+			aperture: v.aperture,
+			focus_distance: v.focus_distance,
+			focus_on: None,
+			blades: v.blades,
+			blade_rotation: v.blade_rotation,
+			shutter_open: v.shutter_open,
+			shutter_close: Some(v.shutter_close),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::{json, Value};
+	use crate::{Scene, SceneError};
+	use crate::testing::{scene_json, sphere, expect_error};
+
+	/// Loads a scene whose camera looks down -z from z = 5, with the entries of
+	/// `camera` added to the camera.
+	fn load(camera: Value, objects: Value) -> Result<Scene, SceneError> {
+		let mut scene = scene_json(json!({ "objects": objects }));
+		for (key, value) in camera.as_object().unwrap() {
+			scene["camera"][key] = value.clone();
+		}
+		Scene::from_json(&scene.to_string())
+	}
+
+	#[test]
+	fn invalid_camera() {
+		let error = expect_error(load(json!({ "look_at": { "x": 0, "y": 0, "z": 5 } }), json!([])), "camera: look_at is the camera's origin");
+		assert!(matches!(error, SceneError::InvalidCamera { .. }));
+		expect_error(load(json!({ "direction": { "x": 0, "y": 0, "z": 0 } }), json!([])), "camera: direction is zero");
+	}
+
+	#[test]
+	fn invalid_focus() {
+		let error = expect_error(load(json!({ "focus_on": 2 }), json!([sphere(json!("Mirror"))])), "camera focus_on objects[2]: there is no such object");
+		assert!(matches!(error, SceneError::InvalidFocus { index: Some(2), .. }));
+
+		let error = expect_error(load(json!({ "focus_on": { "x": 0, "y": 0, "z": 6 } }), json!([])), "camera focus_on: the point is behind the camera");
+		assert!(matches!(error, SceneError::InvalidFocus { index: None, .. }));
+
+		let behind = json!({ "type": "Sphere", "origin": { "x": 0, "y": 0, "z": 8 }, "radius": 1, "material": "Mirror" });
+		expect_error(load(json!({ "focus_on": 0 }), json!([behind])), "camera focus_on objects[0]: the object is behind the camera");
+	}
+}
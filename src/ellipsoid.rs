@@ -1,56 +1,68 @@
 use serde::{Serialize, Deserialize};
-use cgmath::{Point3, Vector3, Rad, Matrix4};
-use cgmath::{Transform, One, MetricSpace, EuclideanSpace};
-use crate::{Ray, Hit, Intersect, Material, VectorFormat, MaterialFormat};
+use cgmath::{Point3, Vector3, Rad};
+use cgmath::{EuclideanSpace, InnerSpace};
+use std::path::Path;
+use std::sync::Arc;
+use crate::{Ray, Hit, Intersect, Material, VectorFormat, MaterialFormat, Aabb, Bounded, Transform, AnimatedTransform, Components, KeyframeFormat, LoadContext, SceneError, spherical_uv, orthogonal_tangent, SPHERE_UV_DENSITY};
 
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(from = "EllipsoidFormat", into = "EllipsoidFormat")]
+#[serde(try_from = "EllipsoidFormat", into = "EllipsoidFormat")]
 pub struct Ellipsoid {
-	pub transform: Matrix4<f64>,
-	pub material: Material,
+	/// Maps the unit sphere onto the ellipsoid.
+	pub transform: AnimatedTransform,
+	pub material: Arc<Material>,
 }
 
-impl Ellipsoid {
-	fn new(origin: Point3<f64>, scale: Vector3<f64>, rot_axis: Vector3<f64>, rot_angle: Rad<f64>, material: Material) -> Ellipsoid {
-		Ellipsoid {
-			transform: Matrix4::one()
-				* Matrix4::from_translation(origin.to_vec())
-				* Matrix4::from_axis_angle(rot_axis, rot_angle)
-				* Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z),
-			material,
-		}
-	}
+/// World bounds of the unit sphere under `transform`.
+fn ellipsoid_bounds(transform: &Transform) -> Aabb {
+	// The unit sphere maps to an ellipsoid whose half extent along each world
+	// axis is the length of the corresponding row of the linear transform.
+	let m = transform.matrix();
+	let extent = Vector3::new(
+		Vector3::new(m.x.x, m.y.x, m.z.x).magnitude(),
+		Vector3::new(m.x.y, m.y.y, m.z.y).magnitude(),
+		Vector3::new(m.x.z, m.y.z, m.z.z).magnitude(),
+	);
+	let center = Point3::from_vec(m.w.truncate());
+	Aabb::new(center - extent, center + extent)
 }
 
 impl Intersect for Ellipsoid {
 	fn intersect(&self, ray: &Ray) -> Option<Hit> {
-		let transform_inv = self.transform.inverse_transform().unwrap();
+		let transform = self.transform.at(ray.time);
+		let object_ray = transform.ray_to_object(ray);
+		let origin = object_ray.origin.to_vec();
 		
-		let trans_ray_origin = transform_inv.transform_point(ray.origin);
-		let trans_ray_origin_vec = Vector3::new(trans_ray_origin.x, trans_ray_origin.y, trans_ray_origin.z); // bruh
-		let trans_ray_direction = transform_inv.transform_vector(ray.direction);
-		
-		let a = cgmath::dot(trans_ray_direction, trans_ray_direction);
-		let b = 2.0 * cgmath::dot(trans_ray_direction, trans_ray_origin_vec);
-		let c = cgmath::dot(trans_ray_origin_vec, trans_ray_origin_vec) - 1.0;
+		let a = cgmath::dot(object_ray.direction, object_ray.direction);
+		let b = 2.0 * cgmath::dot(object_ray.direction, origin);
+		let c = cgmath::dot(origin, origin) - 1.0;
 		let d = (b*b) - (4.0*a*c);
 		if d < 0.0 { return None };
 		let t_plus = (-b + d.sqrt()) / (2.0*a);
 		let t_minus = (-b - d.sqrt()) / (2.0*a);
-		let distance = t_plus.min(t_minus);
+		let distance = if t_minus >= 0.0 { t_minus } else { t_plus };
 		if distance < 0.0 { return None };
-		let position = trans_ray_origin + (trans_ray_direction * distance);
-		let normal = trans_ray_origin_vec + (trans_ray_direction * distance);
+		let object_position = object_ray.at(distance).to_vec();
+		let (uv, tangent) = spherical_uv(object_position.normalize());
+		let normal = transform.normal_to_world(object_position);
+		let tangent = orthogonal_tangent(transform.vector_to_world(tangent), normal);
 		
-		let mut hit = Hit {
-			distance: ray.origin.distance(self.transform.transform_point(position)),
-			position: self.transform.transform_point(position),
-			normal: transform_inv.transform_vector(normal),
+		Some(Hit {
+			distance,
+			position: ray.at(distance),
+			normal,
+			uv,
+			tangent,
+			bitangent: normal.cross(tangent),
+			uv_density: SPHERE_UV_DENSITY / transform.mean_scale(),
 			material: self.material.clone(),
-		};
-		if cgmath::dot(ray.direction, hit.position - ray.origin) < 0.0 { hit.distance *= -1.0 };
-		if hit.distance < 0.0 { return None; }
-		Some(hit)
+		})
+	}
+}
+
+impl Bounded for Ellipsoid {
+	fn bounds(&self) -> Option<Aabb> {
+		Some(self.transform.bounds(ellipsoid_bounds))
 	}
 }
 
@@ -61,23 +73,44 @@ pub struct EllipsoidFormat {
 	pub rot_axis: VectorFormat,
 	pub rot_angle: f64,
 	pub material: MaterialFormat,
+	/// Placement at other times, for a moving ellipsoid.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub motion: Vec<KeyframeFormat>,
 }
 
-impl From<EllipsoidFormat> for Ellipsoid {
-    fn from(v: EllipsoidFormat) -> Ellipsoid { 
-		Ellipsoid::new(v.origin.into(), v.scale.into(), v.rot_axis.into(), Rad(v.rot_angle), v.material.into())
-    }
+impl Ellipsoid {
+	pub fn load(v: EllipsoidFormat, context: &LoadContext) -> Result<Ellipsoid, SceneError> {
+		let components = Components {
+			origin: v.origin.into(),
+			scale: v.scale.into(),
+			rot_axis: v.rot_axis.into(),
+			rot_angle: Rad(v.rot_angle),
+		};
+		Ok(Ellipsoid {
+			transform: AnimatedTransform::load(components, v.motion)?,
+			material: Arc::new(v.material.load(context)?),
+		})
+	}
+}
+
+impl TryFrom<EllipsoidFormat> for Ellipsoid {
+	type Error = SceneError;
+
+	fn try_from(v: EllipsoidFormat) -> Result<Ellipsoid, SceneError> {
+		Ellipsoid::load(v, &LoadContext::new(Path::new("")))
+	}
 }
 
 impl From<Ellipsoid> for EllipsoidFormat {
     fn from(v: Ellipsoid) -> EllipsoidFormat {
-		// TODO: decompose transform matrix
+		let (components, motion) = v.transform.to_format();
         EllipsoidFormat {
-            origin: Point3::new(0.0, 0.0, 0.0).into(),
-			scale: Vector3::new(0.0, 0.0, 0.0).into(),
-			rot_axis: Vector3::new(0.0, 0.0, 0.0).into(),
-			rot_angle: 0.0,
-            material: v.material.into(),
+            origin: components.origin.into(),
+			scale: components.scale.into(),
+			rot_axis: components.rot_axis.into(),
+			rot_angle: components.rot_angle.0,
+            material: Arc::unwrap_or_clone(v.material).into(),
+			motion,
         }
     }
-}
\ No newline at end of file
+}
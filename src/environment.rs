@@ -0,0 +1,358 @@
+use serde::{Serialize, Deserialize};
+use cgmath::Vector3;
+use cgmath::InnerSpace;
+use std::f64::consts::PI;
+use rand::Rng;
+use crate::{RgbFormat, SceneError, LoadContext};
+use crate::sampling::{uniform_sphere, luminance};
+
+/// Light arriving from infinitely far away along rays that miss every object.
+#[derive(Debug, Clone)]
+pub enum Environment {
+	Color(Vector3<f64>),
+	/// Sky that blends from `horizon` up to `zenith`, and down to `ground`
+	/// below the horizon.
+	Gradient {
+		zenith: Vector3<f64>,
+		horizon: Vector3<f64>,
+		ground: Vector3<f64>,
+	},
+	Image(EnvironmentMap),
+}
+
+/// Piecewise constant distribution over `[0, 1)` proportional to `weights`.
+#[derive(Debug, Clone)]
+struct Distribution {
+	weights: Vec<f64>,
+	cdf: Vec<f64>,
+	total: f64,
+}
+
+impl Distribution {
+	fn new(weights: Vec<f64>) -> Distribution {
+		let mut cdf = Vec::with_capacity(weights.len() + 1);
+		cdf.push(0.0);
+		for weight in &weights {
+			cdf.push(cdf.last().unwrap() + weight);
+		}
+		let total = *cdf.last().unwrap();
+		if total > 0.0 {
+			cdf.iter_mut().for_each(|c| *c /= total);
+		} else {
+			// Nothing to prefer, fall back to a uniform distribution.
+			let n = weights.len() as f64;
+			cdf.iter_mut().enumerate().for_each(|(i, c)| *c = i as f64 / n);
+		}
+		Distribution { weights, cdf, total }
+	}
+
+	/// Maps `u` in `[0, 1)` to a position in `[0, 1)` and the bucket it falls in.
+	fn sample(&self, u: f64) -> (f64, usize) {
+		let n = self.weights.len();
+		let index = (self.cdf.partition_point(|&c| c <= u) - 1).min(n - 1);
+		let width = self.cdf[index + 1] - self.cdf[index];
+		let offset = if width > 0.0 { (u - self.cdf[index]) / width } else { 0.5 };
+		((index as f64 + offset) / n as f64, index)
+	}
+
+	/// Density of `sample` at any position in bucket `index`.
+	fn pdf(&self, index: usize) -> f64 {
+		if self.total > 0.0 {
+			self.weights[index] * self.weights.len() as f64 / self.total
+		} else {
+			1.0
+		}
+	}
+}
+
+/// Equirectangular high dynamic range image, with the center of the image in
+/// the -z direction and the top row straight up.
+#[derive(Debug, Clone)]
+pub struct EnvironmentMap {
+	pub width: usize,
+	pub height: usize,
+	pub pixels: Vec<Vector3<f64>>,
+	pub intensity: f64,
+	/// Rotation about the y axis in radians.
+	pub rotation: f64,
+	/// Filename the map was loaded from, kept for serialization.
+	pub filename: String,
+	rows: Vec<Distribution>,
+	marginal: Distribution,
+}
+
+fn parse_pfm(bytes: &[u8]) -> Result<(usize, usize, Vec<Vector3<f64>>), String> {
+	// Header is three whitespace separated tokens after the magic, followed by
+	// exactly one whitespace character.
+	let mut tokens = Vec::new();
+	let mut position = 0;
+	while tokens.len() < 4 {
+		while position < bytes.len() && bytes[position].is_ascii_whitespace() { position += 1; }
+		let start = position;
+		while position < bytes.len() && !bytes[position].is_ascii_whitespace() { position += 1; }
+		if start == position { return Err(String::from("truncated PFM header")); }
+		tokens.push(String::from_utf8_lossy(&bytes[start..position]).into_owned());
+	}
+	let data = &bytes[(position + 1).min(bytes.len())..];
+
+	let channels = match tokens[0].as_str() {
+		"PF" => 3,
+		"Pf" => 1,
+		magic => return Err(format!("unknown PFM magic \"{}\"", magic)),
+	};
+	let parse = |token: &str| token.parse::<usize>().map_err(|_| format!("invalid PFM size \"{}\"", token));
+	let (width, height) = (parse(&tokens[1])?, parse(&tokens[2])?);
+	let scale: f64 = tokens[3].parse().map_err(|_| format!("invalid PFM scale \"{}\"", tokens[3]))?;
+	let pixel_count = width.checked_mul(height).ok_or_else(|| format!("PFM size {}x{} is too large", width, height))?;
+	let byte_count = pixel_count.checked_mul(channels * 4).ok_or_else(|| format!("PFM size {}x{} is too large", width, height))?;
+	if data.len() < byte_count {
+		return Err(format!("PFM data is truncated, expected {}x{} pixels", width, height));
+	}
+
+	let value = |i: usize| {
+		let bytes = [data[4 * i], data[4 * i + 1], data[4 * i + 2], data[4 * i + 3]];
+		(if scale < 0.0 { f32::from_le_bytes(bytes) } else { f32::from_be_bytes(bytes) }) as f64
+	};
+	// Rows are stored bottom to top.
+	let mut pixels = Vec::with_capacity(pixel_count);
+	for y in (0..height).rev() {
+		for x in 0..width {
+			let i = (y * width + x) * channels;
+			pixels.push(if channels == 3 {
+				Vector3::new(value(i), value(i + 1), value(i + 2))
+			} else {
+				Vector3::new(value(i), value(i), value(i))
+			});
+		}
+	}
+	Ok((width, height, pixels))
+}
+
+fn parse_hdr(bytes: &[u8]) -> Result<(usize, usize, Vec<Vector3<f64>>), String> {
+	let image = image::load_from_memory_with_format(bytes, image::ImageFormat::Hdr)
+		.map_err(|error| error.to_string())?
+		.into_rgb32f();
+	let pixels = image.pixels().map(|p| Vector3::new(p[0] as f64, p[1] as f64, p[2] as f64)).collect();
+	Ok((image.width() as usize, image.height() as usize, pixels))
+}
+
+impl EnvironmentMap {
+	pub fn new(width: usize, height: usize, pixels: Vec<Vector3<f64>>, intensity: f64, rotation: f64) -> EnvironmentMap {
+		// Rows near the poles cover less solid angle, weight them by sin(theta).
+		let rows: Vec<Distribution> = (0..height).map(|y| {
+			let sin_theta = (PI * (y as f64 + 0.5) / height as f64).sin();
+			Distribution::new(pixels[y * width..(y + 1) * width].iter().map(|&p| luminance(p).max(0.0) * sin_theta).collect())
+		}).collect();
+		let marginal = Distribution::new(rows.iter().map(|row| row.total).collect());
+		EnvironmentMap {
+			width,
+			height,
+			pixels,
+			intensity,
+			rotation,
+			filename: String::new(),
+			rows,
+			marginal,
+		}
+	}
+
+	/// Decodes a Radiance `.hdr` or PFM image, detected from its header.
+	pub fn from_bytes(bytes: &[u8], intensity: f64, rotation: f64) -> Result<EnvironmentMap, String> {
+		let (width, height, pixels) = if bytes.starts_with(b"PF") || bytes.starts_with(b"Pf") {
+			parse_pfm(bytes)?
+		} else if bytes.starts_with(b"#?") {
+			parse_hdr(bytes)?
+		} else {
+			return Err(String::from("expected a Radiance HDR or PFM image"));
+		};
+		if width == 0 || height == 0 {
+			return Err(String::from("environment map is empty"));
+		}
+		Ok(EnvironmentMap::new(width, height, pixels, intensity, rotation))
+	}
+
+	/// Loads `filename` through `context`.
+	pub fn load(filename: &str, context: &LoadContext, intensity: f64, rotation: f64) -> Result<EnvironmentMap, SceneError> {
+		let map_error = |message: String| SceneError::ImageLoad { path: context.path(filename), message };
+		let bytes = context.read(filename).map_err(|error| map_error(error.to_string()))?;
+		let mut map = EnvironmentMap::from_bytes(&bytes, intensity, rotation).map_err(map_error)?;
+		map.filename = filename.to_string();
+		Ok(map)
+	}
+
+	/// Image coordinates in `[0, 1)` of a unit direction.
+	fn direction_to_uv(&self, direction: Vector3<f64>) -> (f64, f64) {
+		let phi = direction.x.atan2(-direction.z) - self.rotation;
+		let u = (0.5 + phi / (2.0 * PI)).rem_euclid(1.0);
+		let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+		(u, v)
+	}
+
+	fn uv_to_direction(&self, u: f64, v: f64) -> Vector3<f64> {
+		let phi = (u - 0.5) * 2.0 * PI + self.rotation;
+		let theta = v * PI;
+		Vector3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos())
+	}
+
+	fn pixel(&self, x: usize, y: usize) -> Vector3<f64> {
+		self.pixels[y * self.width + x]
+	}
+
+	/// Bilinearly filtered radiance, wrapping horizontally.
+	pub fn lookup(&self, direction: Vector3<f64>) -> Vector3<f64> {
+		let (u, v) = self.direction_to_uv(direction);
+		let x = u * self.width as f64 - 0.5;
+		let y = (v * self.height as f64 - 0.5).clamp(0.0, (self.height - 1) as f64);
+		let (x0, y0) = (x.floor(), y.floor());
+		let (tx, ty) = (x - x0, y - y0);
+		let x0 = (x0 as isize).rem_euclid(self.width as isize) as usize;
+		let x1 = (x0 + 1) % self.width;
+		let y0 = y0 as usize;
+		let y1 = (y0 + 1).min(self.height - 1);
+		let top = self.pixel(x0, y0) * (1.0 - tx) + self.pixel(x1, y0) * tx;
+		let bottom = self.pixel(x0, y1) * (1.0 - tx) + self.pixel(x1, y1) * tx;
+		(top * (1.0 - ty) + bottom * ty) * self.intensity
+	}
+
+	/// Solid angle density of `sample` for a unit direction.
+	fn pdf(&self, direction: Vector3<f64>) -> f64 {
+		let (u, v) = self.direction_to_uv(direction);
+		let x = ((u * self.width as f64) as usize).min(self.width - 1);
+		let y = ((v * self.height as f64) as usize).min(self.height - 1);
+		let sin_theta = (v * PI).sin();
+		if sin_theta <= 0.0 { return 0.0; }
+		self.marginal.pdf(y) * self.rows[y].pdf(x) / (2.0 * PI * PI * sin_theta)
+	}
+
+	/// Picks a direction with probability proportional to the brightness of the map.
+	fn sample<R: Rng>(&self, rng: &mut R) -> (Vector3<f64>, f64) {
+		let (v, y) = self.marginal.sample(rng.gen());
+		let (u, x) = self.rows[y].sample(rng.gen());
+		let sin_theta = (v * PI).sin();
+		if sin_theta <= 0.0 { return (self.uv_to_direction(u, v), 0.0); }
+		let pdf = self.marginal.pdf(y) * self.rows[y].pdf(x) / (2.0 * PI * PI * sin_theta);
+		(self.uv_to_direction(u, v), pdf)
+	}
+}
+
+impl Environment {
+	/// Radiance arriving from the unit direction `direction`.
+	pub fn radiance(&self, direction: Vector3<f64>) -> Vector3<f64> {
+		match self {
+			Environment::Color(color) => *color,
+			Environment::Gradient { zenith, horizon, ground } => {
+				let y = direction.normalize().y;
+				if y >= 0.0 {
+					horizon * (1.0 - y) + zenith * y
+				} else {
+					horizon * (1.0 + y) + ground * -y
+				}
+			}
+			Environment::Image(map) => map.lookup(direction.normalize()),
+		}
+	}
+
+	/// Samples an incoming direction, returning it with its solid angle density.
+	/// Image maps are importance sampled by luminance, everything else uniformly.
+	pub fn sample<R: Rng>(&self, rng: &mut R) -> (Vector3<f64>, f64) {
+		match self {
+			Environment::Image(map) => map.sample(rng),
+			_ => (uniform_sphere(rng), 1.0 / (4.0 * PI)),
+		}
+	}
+
+	/// Density with which `sample` picks the unit direction `direction`.
+	pub fn pdf(&self, direction: Vector3<f64>) -> f64 {
+		match self {
+			Environment::Image(map) => map.pdf(direction),
+			_ => 1.0 / (4.0 * PI),
+		}
+	}
+}
+
+fn default_intensity() -> f64 {
+	1.0
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum EnvironmentFormat {
+	Color {
+		color: RgbFormat,
+	},
+	Gradient {
+		zenith: RgbFormat,
+		horizon: RgbFormat,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		ground: Option<RgbFormat>,
+	},
+	/// Radiance `.hdr` or PFM file, resolved relative to the scene file.
+	Image {
+		filename: String,
+		#[serde(default = "default_intensity")]
+		intensity: f64,
+		#[serde(default)]
+		rotation: f64,
+	},
+}
+
+impl EnvironmentFormat {
+	/// Builds the environment, loading image files through `context`.
+	pub fn load(self, context: &LoadContext) -> Result<Environment, SceneError> {
+		Ok(match self {
+			EnvironmentFormat::Color { color } => Environment::Color(color.into()),
+			EnvironmentFormat::Gradient { zenith, horizon, ground } => {
+				let horizon: Vector3<f64> = horizon.into();
+				Environment::Gradient {
+					zenith: zenith.into(),
+					horizon,
+					ground: ground.map_or(horizon, |ground| ground.into()),
+				}
+			}
+			EnvironmentFormat::Image { filename, intensity, rotation } => {
+				Environment::Image(EnvironmentMap::load(&filename, context, intensity, rotation)?)
+			}
+		})
+	}
+}
+
+impl From<Environment> for EnvironmentFormat {
+	fn from(v: Environment) -> EnvironmentFormat {
+		match v {
+			Environment::Color(color) => EnvironmentFormat::Color { color: color.into() },
+			Environment::Gradient { zenith, horizon, ground } => EnvironmentFormat::Gradient {
+				zenith: zenith.into(),
+				horizon: horizon.into(),
+				ground: Some(ground.into()),
+			},
+			Environment::Image(map) => EnvironmentFormat::Image {
+				filename: map.filename,
+				intensity: map.intensity,
+				rotation: map.rotation,
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn loads_pfm() {
+		let map = EnvironmentMap::from_bytes(include_bytes!("../textures/sky.pfm"), 1.0, 0.0).unwrap();
+		assert_eq!((map.width, map.height), (64, 32));
+		// The sky above is brighter in blue, the ground below is a dim brown.
+		let (up, down) = (map.lookup(Vector3::unit_y()), map.lookup(-Vector3::unit_y()));
+		assert!(up.z > up.x && down.x > down.z && luminance(up) > luminance(down));
+	}
+
+	#[test]
+	fn rejects_oversized_pfm() {
+		let header = format!("PF\n{} {}\n-1.0\n", usize::MAX / 2, 3);
+		assert_eq!(parse_pfm(header.as_bytes()).err().unwrap(), format!("PFM size {}x3 is too large", usize::MAX / 2));
+		let header = format!("Pf\n{} {}\n-1.0\n", usize::MAX / 3, 1);
+		assert!(parse_pfm(header.as_bytes()).err().unwrap().ends_with("is too large"));
+		assert!(parse_pfm(b"PF\n2 2\n-1.0\n").err().unwrap().starts_with("PFM data is truncated"));
+	}
+}
@@ -0,0 +1,190 @@
+use std::fmt;
+use std::path::PathBuf;
+use crate::{TransformError, OBJECT_TYPES, LIGHT_TYPES};
+
+/// Everything that can go wrong while turning scene JSON into a `Scene`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneError {
+	/// Malformed JSON, or JSON that doesn't match the scene format. Only syntax
+	/// errors have a position, `line` and `column` are 0 for the others.
+	Json {
+		message: String,
+		line: usize,
+		column: usize,
+	},
+	UnknownObjectType {
+		index: usize,
+		type_name: String,
+	},
+	UnknownLightType {
+		index: usize,
+		type_name: String,
+	},
+	MissingField {
+		field: String,
+		line: usize,
+		column: usize,
+	},
+	ObjLoad {
+		path: PathBuf,
+		message: String,
+	},
+	/// An environment map or texture that couldn't be read or decoded.
+	ImageLoad {
+		path: PathBuf,
+		message: String,
+	},
+	/// A material refers to a texture name the scene doesn't define.
+	UnknownTexture {
+		name: String,
+	},
+	/// An object or material refers to a material name the scene doesn't define.
+	UnknownMaterial {
+		name: String,
+	},
+	InvalidTransform(TransformError),
+	/// A light without a direction to shine in or a surface to face.
+	InvalidLight {
+		index: usize,
+		message: String,
+	},
+	/// The camera's settings don't give it a view direction.
+	InvalidCamera {
+		message: String,
+	},
+	/// The camera's `focus_on` names a point or object it can't focus on. `index`
+	/// is the object's, if it named one.
+	InvalidFocus {
+		index: Option<usize>,
+		message: String,
+	},
+	/// A `DebugShadows` material averages over the lights, so it needs at least one.
+	NoLights,
+	/// A normal or bump map uses an image decoded as sRGB, which would distort
+	/// the linear data it holds.
+	SrgbMap,
+	/// A `Mix` or `Layered` material blends a `Cutout`, which only works around the blend.
+	CutoutInBlend,
+	/// A named material is called like a built-in one, which its name would mean instead.
+	BuiltinMaterialName {
+		name: String,
+	},
+	/// Named materials that refer to each other in a loop, starting and ending with the same name.
+	MaterialCycle {
+		names: Vec<String>,
+	},
+	/// An error in one entry of the `objects` list.
+	Object {
+		index: usize,
+		error: Box<SceneError>,
+	},
+	/// An error in one of the named `textures`.
+	Texture {
+		name: String,
+		error: Box<SceneError>,
+	},
+	/// An error in one of the named `materials`.
+	Material {
+		name: String,
+		error: Box<SceneError>,
+	},
+}
+
+impl SceneError {
+	fn from_json_error(error: serde_json::Error) -> SceneError {
+		let (line, column) = (error.line(), error.column());
+		let message = error.to_string();
+		let message = message.strip_suffix(&format!(" at line {} column {}", line, column)).unwrap_or(&message);
+		if let Some(field) = message.strip_prefix("missing field `").and_then(|field| field.strip_suffix('`')) {
+			return SceneError::MissingField { field: field.to_string(), line, column };
+		}
+		SceneError::Json { message: message.to_string(), line, column }
+	}
+}
+
+impl From<serde_json::Error> for SceneError {
+	fn from(error: serde_json::Error) -> SceneError {
+		SceneError::from_json_error(error)
+	}
+}
+
+impl From<TransformError> for SceneError {
+	fn from(error: TransformError) -> SceneError {
+		SceneError::InvalidTransform(error)
+	}
+}
+
+/// Formats as ` at line {} column {}`, or nothing for line 0, which serde_json
+/// reports when it doesn't know where an error is.
+struct Position(usize, usize);
+
+impl fmt::Display for Position {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.0 == 0 { return Ok(()); }
+		write!(f, " at line {} column {}", self.0, self.1)
+	}
+}
+
+impl fmt::Display for SceneError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SceneError::Json { message, line, column } => write!(f, "invalid scene json{}: {}", Position(*line, *column), message),
+			SceneError::UnknownObjectType { index, type_name } => write!(f, "objects[{}]: unknown object type \"{}\", expected one of {}", index, type_name, OBJECT_TYPES.join(", ")),
+			SceneError::UnknownLightType { index, type_name } => write!(f, "lights[{}]: unknown light type \"{}\", expected one of {}", index, type_name, LIGHT_TYPES.join(", ")),
+			SceneError::MissingField { field, line, column } => write!(f, "missing field \"{}\"{}", field, Position(*line, *column)),
+			SceneError::ObjLoad { path, message } => write!(f, "failed to load OBJ file {}: {}", path.display(), message),
+			SceneError::ImageLoad { path, message } => write!(f, "failed to load image {}: {}", path.display(), message),
+			SceneError::UnknownTexture { name } => write!(f, "unknown texture \"{}\", named textures must be listed in the scene's textures", name),
+			SceneError::UnknownMaterial { name } => write!(f, "unknown material \"{}\", named materials must be listed in the scene's materials", name),
+			SceneError::InvalidTransform(error) => write!(f, "{}", error),
+			SceneError::InvalidLight { index, message } => write!(f, "lights[{}]: {}", index, message),
+			SceneError::InvalidCamera { message } => write!(f, "camera: {}", message),
+			SceneError::InvalidFocus { index: Some(index), message } => write!(f, "camera focus_on objects[{}]: {}", index, message),
+			SceneError::InvalidFocus { index: None, message } => write!(f, "camera focus_on: {}", message),
+			SceneError::NoLights => write!(f, "the DebugShadows material needs at least one light in the scene"),
+			SceneError::SrgbMap => write!(f, "normal and bump maps hold linear data, set \"srgb\": false on their image textures"),
+			SceneError::CutoutInBlend => write!(f, "Mix and Layered materials can't blend a Cutout, put the Cutout around the blend instead"),
+			SceneError::BuiltinMaterialName { name } => write!(f, "materials[\"{}\"]: the name is taken by the built-in {} material", name, name),
+			SceneError::MaterialCycle { names } => write!(f, "named materials refer to each other in a loop: {}", names.join(" -> ")),
+			SceneError::Object { index, error } => write!(f, "objects[{}]: {}", index, error),
+			SceneError::Texture { name, error } => write!(f, "textures[\"{}\"]: {}", name, error),
+			SceneError::Material { name, error } => write!(f, "materials[\"{}\"]: {}", name, error),
+		}
+	}
+}
+
+impl std::error::Error for SceneError {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn formats_positions_and_wrapped_errors() {
+		let error = SceneError::Json { message: String::from("expected `,` or `}`"), line: 3, column: 7 };
+		assert_eq!(error.to_string(), "invalid scene json at line 3 column 7: expected `,` or `}`");
+		let error = SceneError::MissingField { field: String::from("fovy"), line: 0, column: 0 };
+		assert_eq!(error.to_string(), "missing field \"fovy\"");
+
+		let error = SceneError::UnknownLightType { index: 1, type_name: String::from("Laser") };
+		assert_eq!(error.to_string(), format!("lights[1]: unknown light type \"Laser\", expected one of {}", LIGHT_TYPES.join(", ")));
+		let error = SceneError::InvalidLight { index: 2, message: String::from("direction is zero") };
+		assert_eq!(error.to_string(), "lights[2]: direction is zero");
+
+		let error = SceneError::Material {
+			name: String::from("wall"),
+			error: Box::new(SceneError::Texture { name: String::from("brick"), error: Box::new(SceneError::UnknownTexture { name: String::from("noise") }) }),
+		};
+		assert_eq!(error.to_string(), "materials[\"wall\"]: textures[\"brick\"]: unknown texture \"noise\", named textures must be listed in the scene's textures");
+		let error = SceneError::MaterialCycle { names: vec![String::from("a"), String::from("b"), String::from("a")] };
+		assert_eq!(error.to_string(), "named materials refer to each other in a loop: a -> b -> a");
+	}
+
+	#[test]
+	fn splits_missing_fields_from_json_errors() {
+		let error = serde_json::from_str::<serde_json::Value>("{\n  \"camera\": [1,\n").unwrap_err();
+		assert_eq!(SceneError::from(error), SceneError::Json { message: String::from("EOF while parsing a value"), line: 3, column: 0 });
+		let error = serde_json::from_str::<crate::CameraFormat>(r#"{ "origin": { "x": 0, "y": 0, "z": 0 } }"#).err().unwrap();
+		assert!(matches!(SceneError::from(error), SceneError::MissingField { ref field, line: 1, .. } if field == "fovy"));
+	}
+}
@@ -0,0 +1,182 @@
+use serde::{Serialize, Deserialize};
+use cgmath::{Vector2, Vector3};
+use crate::{LoadContext, SceneError};
+
+/// How an image texture is filtered between texels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TextureFilter {
+	Nearest,
+	Bilinear,
+	/// Bilinear lookups in the two mip levels closest to the ray footprint,
+	/// blended together, so distant surfaces don't alias.
+	#[default]
+	Trilinear,
+}
+
+/// What an image texture shows outside of `[0, 1]` texture coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TextureWrap {
+	#[default]
+	Repeat,
+	Clamp,
+	/// Repeats the image, flipping every other copy so edges line up.
+	Mirror,
+}
+
+/// One level of a mip chain, stored row by row from the top left.
+#[derive(Debug, Clone)]
+struct MipLevel {
+	width: usize,
+	height: usize,
+	texels: Vec<[f32; 3]>,
+}
+
+impl MipLevel {
+	/// Half the size of `self`, each texel the average of the texels it covers.
+	fn downsample(&self) -> MipLevel {
+		let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+		let mut texels = Vec::with_capacity(width * height);
+		for y in 0..height {
+			for x in 0..width {
+				let mut sum = [0.0; 3];
+				for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+					let texel = self.texels[(2 * y + dy).min(self.height - 1) * self.width + (2 * x + dx).min(self.width - 1)];
+					for c in 0..3 { sum[c] += texel[c] / 4.0; }
+				}
+				texels.push(sum);
+			}
+		}
+		MipLevel { width, height, texels }
+	}
+}
+
+/// Converts an sRGB encoded channel in `[0, 1]` to linear.
+fn srgb_to_linear(v: f32) -> f32 {
+	if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+fn wrap(i: i64, size: usize, mode: TextureWrap) -> usize {
+	let size = size as i64;
+	(match mode {
+		TextureWrap::Repeat => i.rem_euclid(size),
+		TextureWrap::Clamp => i.clamp(0, size - 1),
+		TextureWrap::Mirror => {
+			let i = i.rem_euclid(2 * size);
+			if i < size { i } else { 2 * size - 1 - i }
+		}
+	}) as usize
+}
+
+/// A decoded PNG or JPEG image with its mip chain, in linear color.
+///
+/// Texture coordinate (0, 0) is the bottom left of the image, as in OBJ files.
+#[derive(Debug, Clone)]
+pub struct ImageTexture {
+	levels: Vec<MipLevel>,
+	pub filter: TextureFilter,
+	pub wrap: TextureWrap,
+	/// Whether the image was stored in sRGB and converted to linear on load.
+	pub srgb: bool,
+	/// Whether the texels hold the image's alpha channel instead of its colors.
+	pub alpha: bool,
+	/// Filename the image was loaded from, kept for serialization.
+	pub filename: String,
+}
+
+impl ImageTexture {
+	/// Builds a texture from linear texels stored row by row from the top left.
+	pub fn new(width: usize, height: usize, texels: Vec<[f32; 3]>, filter: TextureFilter, wrap: TextureWrap) -> ImageTexture {
+		let mut levels = vec![MipLevel { width, height, texels }];
+		if filter == TextureFilter::Trilinear {
+			while let Some(last) = levels.last().filter(|level| level.width > 1 || level.height > 1) {
+				let next = last.downsample();
+				levels.push(next);
+			}
+		}
+		ImageTexture {
+			levels,
+			filter,
+			wrap,
+			srgb: false,
+			alpha: false,
+			filename: String::new(),
+		}
+	}
+
+	/// Decodes a PNG or JPEG image, converting it from sRGB to linear if `srgb` is
+	/// set. With `alpha` set every channel holds the image's alpha instead, which
+	/// is linear already and one for images without it.
+	pub fn from_bytes(bytes: &[u8], filter: TextureFilter, wrap: TextureWrap, srgb: bool, alpha: bool) -> Result<ImageTexture, String> {
+		let image = image::load_from_memory(bytes).map_err(|error| error.to_string())?.into_rgba32f();
+		let (width, height) = (image.width() as usize, image.height() as usize);
+		if width == 0 || height == 0 {
+			return Err(String::from("image is empty"));
+		}
+		let decode = |v: f32| if srgb { srgb_to_linear(v) } else { v };
+		let texels = image.pixels().map(|pixel| if alpha { [pixel[3]; 3] } else { [decode(pixel[0]), decode(pixel[1]), decode(pixel[2])] }).collect();
+		let mut texture = ImageTexture::new(width, height, texels, filter, wrap);
+		texture.srgb = srgb;
+		texture.alpha = alpha;
+		Ok(texture)
+	}
+
+	/// Loads `filename` through `context`.
+	pub fn load(filename: &str, context: &LoadContext, filter: TextureFilter, wrap: TextureWrap, srgb: bool, alpha: bool) -> Result<ImageTexture, SceneError> {
+		let map_error = |message: String| SceneError::ImageLoad { path: context.path(filename), message };
+		let bytes = context.read(filename).map_err(|error| map_error(error.to_string()))?;
+		let mut texture = ImageTexture::from_bytes(&bytes, filter, wrap, srgb, alpha).map_err(map_error)?;
+		texture.filename = filename.to_string();
+		Ok(texture)
+	}
+
+	pub fn width(&self) -> usize {
+		self.levels[0].width
+	}
+
+	pub fn height(&self) -> usize {
+		self.levels[0].height
+	}
+
+	fn texel(&self, level: &MipLevel, x: i64, y: i64) -> Vector3<f64> {
+		let texel = level.texels[wrap(y, level.height, self.wrap) * level.width + wrap(x, level.width, self.wrap)];
+		Vector3::new(texel[0] as f64, texel[1] as f64, texel[2] as f64)
+	}
+
+	fn nearest(&self, level: &MipLevel, uv: Vector2<f64>) -> Vector3<f64> {
+		let x = (uv.x * level.width as f64).floor() as i64;
+		let y = ((1.0 - uv.y) * level.height as f64).floor() as i64;
+		self.texel(level, x, y)
+	}
+
+	fn bilinear(&self, level: &MipLevel, uv: Vector2<f64>) -> Vector3<f64> {
+		let x = uv.x * level.width as f64 - 0.5;
+		let y = (1.0 - uv.y) * level.height as f64 - 0.5;
+		let (x0, y0) = (x.floor(), y.floor());
+		let (tx, ty) = (x - x0, y - y0);
+		let (x0, y0) = (x0 as i64, y0 as i64);
+		let top = self.texel(level, x0, y0) * (1.0 - tx) + self.texel(level, x0 + 1, y0) * tx;
+		let bottom = self.texel(level, x0, y0 + 1) * (1.0 - tx) + self.texel(level, x0 + 1, y0 + 1) * tx;
+		top * (1.0 - ty) + bottom * ty
+	}
+
+	/// Color at `uv`. `footprint` is the width of the area to average over in
+	/// texture coordinates, only used by trilinear filtering.
+	pub fn lookup(&self, uv: Vector2<f64>, footprint: f64) -> Vector3<f64> {
+		match self.filter {
+			TextureFilter::Nearest => self.nearest(&self.levels[0], uv),
+			TextureFilter::Bilinear => self.bilinear(&self.levels[0], uv),
+			TextureFilter::Trilinear => {
+				let texels = footprint * self.width().max(self.height()) as f64;
+				let lod = if texels > 1.0 { texels.log2().min((self.levels.len() - 1) as f64) } else { 0.0 };
+				let level = lod.floor() as usize;
+				let t = lod - level as f64;
+				let color = self.bilinear(&self.levels[level], uv);
+				if t > 0.0 {
+					color * (1.0 - t) + self.bilinear(&self.levels[level + 1], uv) * t
+				} else {
+					color
+				}
+			}
+		}
+	}
+}
@@ -1,23 +1,34 @@
 use serde::{Serialize, Deserialize};
-use cgmath::{Point3, Vector3};
-use cgmath::{InnerSpace, ElementWise, MetricSpace};
+use cgmath::{Point3, Vector2, Vector3};
+use cgmath::{InnerSpace, ElementWise};
 use core::cmp::Ordering;
-use wasm_bindgen::prelude::*;
-use wasm_bindgen::Clamped;
-use web_sys::{CanvasRenderingContext2d, ImageData};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use rand::Rng;
-extern crate console_error_panic_hook;
-use std::panic;
-
-pub use plane::Plane;
-pub use sphere::Sphere;
-pub use camera::Camera;
-pub use ellipsoid::Ellipsoid;
-pub use mesh::Mesh;
+
+pub use plane::{Plane, PlaneFormat};
+pub use sphere::{Sphere, SphereFormat};
+pub use camera::{Camera, CameraFormat, FocusFormat, Projection, FisheyeMapping};
+pub use ellipsoid::{Ellipsoid, EllipsoidFormat};
+pub use mesh::{Mesh, MeshFormat};
 pub use material::Material;
 pub use material::MaterialFormat;
-pub use light::Light;
+pub use material::{Metal, MetalFormat, Factor, FactorFormat, FresnelFormat};
+pub use light::{Light, LightSample};
 pub use format::{VectorFormat, RgbFormat};
+pub use environment::{Environment, EnvironmentMap, EnvironmentFormat};
+pub use texture::{Texture, TextureFormat, TextureMapping};
+pub use image_texture::{ImageTexture, TextureFilter, TextureWrap};
+pub use aabb::{Aabb, Bounded};
+pub use bvh::{Bvh, BvhStats};
+pub use transform::{Transform, TransformError, AnimatedTransform, Components, Keyframes, Interpolate, KeyframeFormat};
+pub use error::SceneError;
+pub use render::{render, RenderSettings, Image, Integrator};
+pub use path::trace_path;
+#[cfg(feature = "wasm")]
+pub use wasm::{draw, add_file, clear_files};
 
 mod plane;
 mod sphere;
@@ -27,11 +38,35 @@ mod mesh;
 mod material;
 mod light;
 mod format;
+mod environment;
+mod texture;
+mod image_texture;
+mod noise;
+mod bump;
+mod microfacet;
+mod aabb;
+mod bvh;
+mod transform;
+mod error;
+mod render;
+mod path;
+mod sampling;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "wasm")]
+mod wasm;
 
 #[derive(Debug, PartialEq)]
 pub struct Ray {
 	pub origin: Point3<f64>,
 	pub direction: Vector3<f64>,
+	/// Angle in radians the area a ray stands for widens by per unit of distance,
+	/// about the angle of a pixel for camera rays. Zero for rays that don't look
+	/// up textures, like shadow rays.
+	pub spread: f64,
+	/// Moment within the camera's shutter interval the ray travels at. Moving
+	/// objects are intersected where they are at that moment.
+	pub time: f64,
 }
 
 impl Ray {
@@ -45,7 +80,112 @@ pub struct Hit {
 	pub distance: f64,
 	pub position: Point3<f64>,
 	pub normal: Vector3<f64>,
-	pub material: Material,
+	/// Texture coordinates of the hit point.
+	pub uv: Vector2<f64>,
+	/// Unit vector along the surface in the direction of increasing `uv.x`,
+	/// perpendicular to `normal`.
+	pub tangent: Vector3<f64>,
+	/// Unit vector along the surface in the direction of increasing `uv.y`,
+	/// perpendicular to `normal` and `tangent`.
+	pub bitangent: Vector3<f64>,
+	/// Roughly how much `uv` changes per unit of distance along the surface.
+	pub uv_density: f64,
+	pub material: Arc<Material>,
+}
+
+/// Spherical texture coordinates of a point `p` on the unit sphere, with the
+/// seam facing -z, together with the direction of increasing u.
+pub(crate) fn spherical_uv(p: Vector3<f64>) -> (Vector2<f64>, Vector3<f64>) {
+	let u = 0.5 + p.x.atan2(p.z) / (2.0 * std::f64::consts::PI);
+	let v = 1.0 - p.y.clamp(-1.0, 1.0).acos() / std::f64::consts::PI;
+	(Vector2::new(u, v), Vector3::new(p.z, 0.0, -p.x))
+}
+
+/// Approximate width in texture coordinates of the surface around `hit` covered
+/// by `ray`, stretched where the ray hits at a grazing angle.
+pub(crate) fn texture_footprint(ray: &Ray, hit: &Hit) -> f64 {
+	let length = ray.direction.magnitude();
+	let cos_theta = (cgmath::dot(ray.direction, hit.normal) / length).abs().max(0.05);
+	hit.distance * length * ray.spread / cos_theta * hit.uv_density
+}
+
+/// `Hit::uv_density` of a unit sphere mapped with `spherical_uv`, between that
+/// of u, which wraps around the equator, and v, which runs pole to pole.
+pub(crate) const SPHERE_UV_DENSITY: f64 = std::f64::consts::FRAC_1_SQRT_2 / std::f64::consts::PI;
+
+/// Replaces named, cut out, normal and bump mapped materials on `hit` with the
+/// materials they wrap, bending its normal, tangent and bitangent as the maps say.
+pub(crate) fn apply_modifiers(ray: &Ray, hit: &mut Hit) {
+	loop {
+		let footprint = texture_footprint(ray, hit);
+		let (normal, material) = match &*hit.material {
+			Material::Named { material, .. } | Material::Cutout { material, .. } => {
+				hit.material = material.clone();
+				continue;
+			}
+			Material::NormalMap { material, map, strength } => (bump::normal_map(hit, map, *strength, footprint), material.clone()),
+			Material::Bump { material, map, depth } => (bump::bump_map(hit, map, *depth, footprint), material.clone()),
+			_ => return,
+		};
+		let tangent = orthogonal_tangent(hit.tangent, normal);
+		let bitangent = normal.cross(tangent);
+		hit.bitangent = if cgmath::dot(bitangent, hit.bitangent) < 0.0 { -bitangent } else { bitangent };
+		hit.tangent = tangent;
+		hit.normal = normal;
+		hit.material = material;
+	}
+}
+
+/// Replaces blends on `hit`, and the modifiers they contain, with one of the
+/// materials they blend picked at random in proportion to its weight, which
+/// averages out to the blend. Returns what the picked material's light is
+/// scaled by, which is less than 1 for a base seen through a coat.
+pub(crate) fn pick_material<R: Rng>(ray: &Ray, hit: &mut Hit, rng: &mut R) -> f64 {
+	let mut weight = 1.0;
+	loop {
+		apply_modifiers(ray, hit);
+		let footprint = texture_footprint(ray, hit);
+		let material = match &*hit.material {
+			Material::Mix { a, b, factor } => {
+				if rng.gen::<f64>() < factor.evaluate(ray, hit, footprint) { b.clone() } else { a.clone() }
+			}
+			Material::Layered { base, coat, coat_weight } => {
+				let coat_weight = coat_weight.evaluate(ray, hit, footprint);
+				if rng.gen::<f64>() < coat_weight { coat.clone() } else {
+					weight *= 1.0 - coat_weight;
+					base.clone()
+				}
+			}
+			_ => return weight,
+		};
+		hit.material = material;
+	}
+}
+
+impl Hit {
+	/// The same hit on a surface made of `material`.
+	fn with_material(&self, material: &Arc<Material>) -> Hit {
+		Hit { material: material.clone(), ..*self }
+	}
+}
+
+/// `a * a_weight + b * b_weight` for the colors of two blended materials, where
+/// a material that returned none counts as black unless neither did.
+fn blend(a: Option<Vector3<f64>>, a_weight: f64, b: Option<Vector3<f64>>, b_weight: f64) -> Option<Vector3<f64>> {
+	if a.is_none() && b.is_none() { return None; }
+	let black = Vector3::new(0.0, 0.0, 0.0);
+	Some(a.unwrap_or(black) * a_weight + b.unwrap_or(black) * b_weight)
+}
+
+/// Makes `tangent` a unit vector perpendicular to the unit `normal`, picking an
+/// arbitrary one if it is degenerate, for example at the poles of a sphere.
+pub(crate) fn orthogonal_tangent(tangent: Vector3<f64>, normal: Vector3<f64>) -> Vector3<f64> {
+	let tangent = tangent - normal * cgmath::dot(normal, tangent);
+	if tangent.magnitude2() > 1e-12 {
+		tangent.normalize()
+	} else {
+		sampling::orthonormal_basis(normal).0
+	}
 }
 
 impl PartialOrd for Hit {
@@ -78,8 +218,8 @@ pub trait Intersect {
 	fn intersect(&self, ray: &Ray) -> Option<Hit>;
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "type")]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "ObjectFormat", into = "ObjectFormat")]
 pub enum Object {
 	Plane(Plane),
 	Sphere(Sphere),
@@ -89,168 +229,665 @@ pub enum Object {
 impl Intersect for Object {
 	fn intersect(&self, ray: &Ray) -> Option<Hit> {
 		match self {
-			Object::Plane(object) => object.intersect(&ray),
-			Object::Sphere(object) => object.intersect(&ray),
-			Object::Ellipsoid(object) => object.intersect(&ray),
-			Object::Mesh(object) => object.intersect(&ray),
+			Object::Plane(object) => object.intersect(ray),
+			Object::Sphere(object) => object.intersect(ray),
+			Object::Ellipsoid(object) => object.intersect(ray),
+			Object::Mesh(object) => object.intersect(ray),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ObjectFormat {
+	Plane(PlaneFormat),
+	Sphere(SphereFormat),
+	Ellipsoid(EllipsoidFormat),
+	Mesh(MeshFormat),
+}
+
+impl ObjectFormat {
+	/// Builds the object, loading any files and named textures it refers to through `context`.
+	pub fn load(self, context: &LoadContext) -> Result<Object, SceneError> {
+		Ok(match self {
+			ObjectFormat::Plane(v) => Object::Plane(Plane::load(v, context)?),
+			ObjectFormat::Sphere(v) => Object::Sphere(Sphere::load(v, context)?),
+			ObjectFormat::Ellipsoid(v) => Object::Ellipsoid(Ellipsoid::load(v, context)?),
+			ObjectFormat::Mesh(v) => Object::Mesh(Mesh::load(v, context)?),
+		})
+	}
+}
+
+impl TryFrom<ObjectFormat> for Object {
+	type Error = SceneError;
+
+	fn try_from(v: ObjectFormat) -> Result<Object, SceneError> {
+		v.load(&LoadContext::new(Path::new("")))
+	}
+}
+
+impl From<Object> for ObjectFormat {
+	fn from(v: Object) -> ObjectFormat {
+		match v {
+			Object::Plane(object) => ObjectFormat::Plane(object.into()),
+			Object::Sphere(object) => ObjectFormat::Sphere(object.into()),
+			Object::Ellipsoid(object) => ObjectFormat::Ellipsoid(object.into()),
+			Object::Mesh(object) => ObjectFormat::Mesh(object.into()),
+		}
+	}
+}
+
+impl Object {
+	pub fn material(&self) -> &Material {
+		match self {
+			Object::Plane(object) => &object.material,
+			Object::Sphere(object) => &object.material,
+			Object::Ellipsoid(object) => &object.material,
+			Object::Mesh(object) => &object.material,
 		}
 	}
 }
-pub type Objects = Vec<Object>;
+
+impl Bounded for Object {
+	fn bounds(&self) -> Option<Aabb> {
+		match self {
+			Object::Plane(object) => object.bounds(),
+			Object::Sphere(object) => object.bounds(),
+			Object::Ellipsoid(object) => object.bounds(),
+			Object::Mesh(object) => object.bounds(),
+		}
+	}
+}
+
+/// The objects of a scene in their original order, with every bounded object
+/// stored in a BVH and unbounded ones (planes) kept in a list that is tested
+/// against every ray.
+#[derive(Clone, Default)]
+pub struct Objects {
+	objects: Vec<Object>,
+	/// Index in `objects` of each object in the BVH, by BVH primitive index.
+	bounded: Vec<usize>,
+	unbounded: Vec<usize>,
+	bvh: Bvh,
+}
+
+impl Objects {
+	pub fn new(objects: Vec<Object>) -> Objects {
+		let mut bounded = Vec::new();
+		let mut bounds = Vec::new();
+		let mut unbounded = Vec::new();
+		for (index, object) in objects.iter().enumerate() {
+			match object.bounds() {
+				Some(object_bounds) => {
+					bounds.push(object_bounds);
+					bounded.push(index);
+				}
+				None => unbounded.push(index),
+			}
+		}
+		Objects {
+			bvh: Bvh::new(&bounds),
+			objects,
+			bounded,
+			unbounded,
+		}
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &Object> {
+		self.objects.iter()
+	}
+
+	pub fn len(&self) -> usize {
+		self.objects.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+impl From<Vec<Object>> for Objects {
+	fn from(v: Vec<Object>) -> Objects {
+		Objects::new(v)
+	}
+}
+
+impl FromIterator<Object> for Objects {
+	fn from_iter<I: IntoIterator<Item = Object>>(iter: I) -> Objects {
+		Objects::new(iter.into_iter().collect())
+	}
+}
+
+impl Objects {
+	/// Nearest hit along `ray`, cut out or not.
+	fn nearest(&self, ray: &Ray) -> Option<Hit> {
+		let bounded = self.bvh.intersect(ray, |i| self.objects[self.bounded[i]].intersect(ray));
+		let unbounded = self.unbounded.iter().filter_map(|&index|{
+			self.objects[index].intersect(ray)
+		}).min();
+		bounded.into_iter().chain(unbounded).min()
+	}
+}
+
+/// Whether `ray` stops at `hit` rather than passing through a cut out part of
+/// the surface. Fractional opacities are decided by a hash of the ray, since
+/// intersection has no random number generator, which still averages out over
+/// the many rays of a pixel.
+fn stops_at(ray: &Ray, hit: &Hit) -> bool {
+	let mut material = &*hit.material;
+	loop {
+		material = match material {
+			Material::Named { material, .. } | Material::NormalMap { material, .. } | Material::Bump { material, .. } => material,
+			Material::Cutout { material, opacity } => {
+				let opacity = opacity.evaluate(hit.uv, hit.position, texture_footprint(ray, hit)).x;
+				if opacity <= 0.0 { return false; }
+				if opacity < 1.0 {
+					let (o, d) = (ray.origin, ray.direction);
+					if sampling::hash_to_unit(&[o.x, o.y, o.z, d.x, d.y, d.z, ray.time, hit.distance]) >= opacity { return false; }
+				}
+				material
+			}
+			// Including blends, which can't contain cutouts.
+			_ => return true,
+		};
+	}
+}
 
 impl Intersect for Objects {
+	/// Nearest hit along `ray` that isn't cut out.
 	fn intersect(&self, ray: &Ray) -> Option<Hit> {
-		self.iter().filter_map(|component|{
-			component.intersect(ray)
-		}).min()
+		let mut hit = self.nearest(ray)?;
+		let mut skipped = 0.0;
+		while !stops_at(ray, &hit) {
+			// Carry on from just past the cut out surface.
+			skipped += hit.distance + 0.0001;
+			let continued = Ray {
+				origin: ray.at(skipped),
+				direction: ray.direction,
+				spread: ray.spread,
+				time: ray.time,
+			};
+			hit = self.nearest(&continued)?;
+		}
+		hit.distance += skipped;
+		Some(hit)
 	}
 }
 
-#[derive(Serialize, Deserialize)]
+/// Where the files and named textures and materials a scene refers to come from.
+pub struct LoadContext<'a> {
+	/// Directory relative filenames are resolved in, usually the one containing the scene file.
+	pub base_dir: &'a Path,
+	/// Files supplied in memory, checked before the file system and matched by
+	/// full filename or just its last component. The browser has no file system,
+	/// so it registers images here.
+	pub files: Option<&'a HashMap<String, Vec<u8>>>,
+	/// The scene's named textures, shared by every material that uses them.
+	pub textures: BTreeMap<String, Arc<Texture>>,
+	/// The scene's named materials, shared by every object that uses them.
+	pub materials: BTreeMap<String, Arc<Material>>,
+}
+
+impl<'a> LoadContext<'a> {
+	pub fn new(base_dir: &'a Path) -> LoadContext<'a> {
+		LoadContext {
+			base_dir,
+			files: None,
+			textures: BTreeMap::new(),
+			materials: BTreeMap::new(),
+		}
+	}
+
+	pub fn path(&self, filename: &str) -> PathBuf {
+		self.base_dir.join(filename)
+	}
+
+	/// Contents of `filename`, from the in-memory files if it is one of them.
+	pub fn read(&self, filename: &str) -> std::io::Result<Cow<'a, [u8]>> {
+		if let Some(files) = self.files {
+			let name = Path::new(filename).file_name().and_then(|name| name.to_str());
+			if let Some(bytes) = files.get(filename).or_else(|| name.and_then(|name| files.get(name))) {
+				return Ok(Cow::Borrowed(bytes));
+			}
+		}
+		std::fs::read(self.path(filename)).map(Cow::Owned)
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "SceneFormat", into = "SceneFormat")]
 pub struct Scene {
     pub camera: Camera,
 	pub lights: Vec<Light>,
 	pub objects: Objects,
+	/// What rays that miss every object see, black if unset.
+	pub environment: Option<Environment>,
+	/// Textures materials can refer to by name.
+	pub textures: BTreeMap<String, Arc<Texture>>,
+	/// Materials objects can refer to by name.
+	pub materials: BTreeMap<String, Arc<Material>>,
 }
 
-#[allow(non_snake_case)]
-fn reflect(I: Vector3<f64>, N: Vector3<f64>) -> Vector3<f64> {
-	I - N * cgmath::dot(N, I) * 2.0f64
+#[derive(Serialize, Deserialize)]
+pub struct SceneFormat {
+	pub camera: CameraFormat,
+	pub lights: Vec<Light>,
+	pub objects: Vec<ObjectFormat>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub environment: Option<EnvironmentFormat>,
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub textures: BTreeMap<String, TextureFormat>,
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub materials: BTreeMap<String, MaterialFormat>,
 }
 
-pub fn shade_ray(objects: &Objects, lights: &Vec<Light>, camera: &Camera, ray: &Ray, bounces_remaining: u32) -> Option<Vector3<f64>> {
-	if let Some(hit) = objects.intersect(&ray) {
-		match hit.material {
-			Material::DebugPosition => {
-				return Some(Vector3::new(hit.position.x, hit.position.y, hit.position.z));
-			}
-			Material::DebugNormals => {
-				return Some(hit.normal);
+impl SceneFormat {
+	/// Builds the scene, resolving mesh and image filenames relative to `base_dir`
+	/// (usually the directory containing the scene file).
+	pub fn load(self, base_dir: &Path) -> Result<Scene, SceneError> {
+		self.load_with_context(LoadContext::new(base_dir))
+	}
+
+	/// Builds the scene, reading files through `context`. Named textures are
+	/// loaded first and can't refer to each other, then named materials, each
+	/// after the named materials it refers to.
+	pub fn load_with_context(self, mut context: LoadContext) -> Result<Scene, SceneError> {
+		let mut textures = BTreeMap::new();
+		for (name, texture) in self.textures {
+			let texture = texture.load(&context).map_err(|error| SceneError::Texture { name: name.clone(), error: Box::new(error) })?;
+			textures.insert(name, Arc::new(texture));
+		}
+		context.textures = textures;
+		let mut materials = self.materials;
+		let names: Vec<String> = materials.keys().cloned().collect();
+		for name in &names {
+			if material::is_builtin_name(name) {
+				return Err(SceneError::BuiltinMaterialName { name: name.clone() });
 			}
-			Material::DebugShadows => {
-				let mut ray_color = Vector3::new(0.0, 0.0, 0.0);
-				
-				for light in lights {
-					if cgmath::dot(hit.normal, light.position - hit.position) < 0.0 { 
-						ray_color += Vector3::new(1.0, 0.0, 0.0);
-						continue;
-					}
-					
-					let mut shadow_ray = Ray {
-						origin: hit.position,
-						direction: (light.position - hit.position).normalize(),
-					};
-					shadow_ray.origin = shadow_ray.at(0.0001);
-					
-					if let Some(shadow_hit) = objects.intersect(&shadow_ray){
-						if shadow_hit.distance <= light.position.distance(hit.position) {
-							ray_color += Vector3::new(0.0, 1.0, 0.0);
-							continue;
-						}
+		}
+		for name in &names {
+			load_named_material(name, &mut materials, &mut context, &mut Vec::new())?;
+		}
+
+		for (index, light) in self.lights.iter().enumerate() {
+			light.validate().map_err(|message| SceneError::InvalidLight { index, message })?;
+		}
+		let objects = self.objects.into_iter().enumerate().map(|(index, object)| {
+			object.load(&context).map_err(|error| SceneError::Object { index, error: Box::new(error) })
+		}).collect::<Result<Vec<Object>, _>>()?;
+		let camera = self.camera.load(&objects)?;
+		let objects = Objects::new(objects);
+		if self.lights.is_empty() && objects.iter().any(|object| object.material().any(&|material| matches!(material, Material::DebugShadows))) {
+			return Err(SceneError::NoLights);
+		}
+		Ok(Scene {
+			camera,
+			lights: self.lights,
+			objects,
+			environment: self.environment.map(|environment| environment.load(&context)).transpose()?,
+			textures: context.textures,
+			materials: context.materials,
+		})
+	}
+}
+
+/// Loads the named material `name`, if it is still in `formats`, after the
+/// named materials it refers to. `loading` holds the names being loaded
+/// further up, which referring to again would never end.
+fn load_named_material(name: &str, formats: &mut BTreeMap<String, MaterialFormat>, context: &mut LoadContext, loading: &mut Vec<String>) -> Result<(), SceneError> {
+	let Some(format) = formats.remove(name) else {
+		if let Some(start) = loading.iter().position(|loading| loading == name) {
+			let mut names = loading[start..].to_vec();
+			names.push(name.to_string());
+			return Err(SceneError::MaterialCycle { names });
+		}
+		// Loaded already, or unknown, which loading the material referring to it reports.
+		return Ok(());
+	};
+	loading.push(name.to_string());
+	for reference in format.references() {
+		load_named_material(reference, formats, context, loading)?;
+	}
+	loading.pop();
+	let material = format.load(context).map_err(|error| SceneError::Material { name: name.to_string(), error: Box::new(error) })?;
+	context.materials.insert(name.to_string(), Arc::new(material));
+	Ok(())
+}
+
+impl TryFrom<SceneFormat> for Scene {
+	type Error = SceneError;
+
+	fn try_from(v: SceneFormat) -> Result<Scene, SceneError> {
+		v.load(Path::new(""))
+	}
+}
+
+const OBJECT_TYPES: [&str; 4] = ["Plane", "Sphere", "Ellipsoid", "Mesh"];
+const LIGHT_TYPES: [&str; 6] = ["Point", "Directional", "Spot", "Rect", "Disk", "Sphere"];
+
+impl Scene {
+	pub fn from_json(json: &str) -> Result<Scene, SceneError> {
+		Scene::from_json_with_base_dir(json, Path::new(""))
+	}
+
+	/// Parses and loads a scene, resolving mesh filenames relative to `base_dir`.
+	pub fn from_json_with_base_dir(json: &str, base_dir: &Path) -> Result<Scene, SceneError> {
+		Scene::parse(json)?.load(base_dir)
+	}
+
+	/// Parses and loads a scene whose image files are supplied in memory by name.
+	pub fn from_json_with_files(json: &str, files: &HashMap<String, Vec<u8>>) -> Result<Scene, SceneError> {
+		let mut context = LoadContext::new(Path::new(""));
+		context.files = Some(files);
+		Scene::parse(json)?.load_with_context(context)
+	}
+
+	fn parse(json: &str) -> Result<SceneFormat, SceneError> {
+		// Check object and light tags up front, serde only reports them as an unknown variant.
+		let value = serde_json::from_str::<serde_json::Value>(json)?;
+		if let Some(objects) = value.get("objects").and_then(|objects| objects.as_array()) {
+			for (index, object) in objects.iter().enumerate() {
+				if let Some(type_name) = object.get("type").and_then(|type_name| type_name.as_str()) {
+					if !OBJECT_TYPES.contains(&type_name) {
+						return Err(SceneError::UnknownObjectType { index, type_name: type_name.to_string() });
 					}
-					ray_color += Vector3::new(0.0, 0.0, 1.0);
 				}
-				return Some(ray_color / lights.len() as f64);
-			}
-			Material::Emissive { color } => {
-				return Some(color);
 			}
-			Material::Mirror => {
-				if bounces_remaining == 0 { return None; }
-				let mut reflection_ray = Ray {
-					origin: hit.position,
-					direction: reflect(ray.direction, hit.normal),
-				};
-				reflection_ray.origin = reflection_ray.at(0.0001);
-				let reflection_camera = Camera {
-					origin: reflection_ray.origin,
-					direction: reflection_ray.direction,
-					fovy: camera.fovy,
-					aspect: camera.aspect,
-				};
-				return shade_ray(objects, lights, &reflection_camera, &reflection_ray, bounces_remaining-1);
-			}
-			Material::BlinnPhong { ambient, diffuse, specular, intensity } => {
-				let mut ray_color = ambient;
-		
-				for light in lights {
-					if cgmath::dot(hit.normal, light.position - hit.position) < 0.0 { continue; }
-					
-					let mut shadow_ray = Ray {
-						origin: hit.position,
-						direction: (light.position - hit.position).normalize(),
-					};
-					shadow_ray.origin = shadow_ray.at(0.0001);
-					
-					if let Some(shadow_hit) = objects.intersect(&shadow_ray){
-						if shadow_hit.distance <= light.position.distance(hit.position) {
-							continue;
-						}
-					}
-					let r = light.position.distance(hit.position);
-					ray_color += {
-						let p_nor = hit.normal.normalize();
-						let p_eye = (camera.origin - hit.position).normalize();
-						let p_light = (light.position - hit.position).normalize();
-						let p_half = (p_light + p_eye).normalize();
-						let cd = diffuse * 0.0_f64.max(cgmath::dot(p_light, p_nor));
-						let cs = specular * 0.0_f64.max(cgmath::dot(p_half, p_nor)).powf(intensity);
-						let (constant, linear, quadratic) = light.attenuation.unwrap_or((1.0, 0.0, 0.0));
-						let attenuation = 1.0 / (constant + (linear*r) + (quadratic*r*r));
-						(light.color * light.intensity).mul_element_wise(cd + cs) * attenuation
+		}
+		if let Some(lights) = value.get("lights").and_then(|lights| lights.as_array()) {
+			for (index, light) in lights.iter().enumerate() {
+				if let Some(type_name) = light.get("type").and_then(|type_name| type_name.as_str()) {
+					if !LIGHT_TYPES.contains(&type_name) {
+						return Err(SceneError::UnknownLightType { index, type_name: type_name.to_string() });
 					}
 				}
-				
-				return Some(ray_color);
 			}
 		}
+		Ok(SceneFormat::deserialize(value)?)
 	}
-	None
 }
 
-#[wasm_bindgen]
-pub fn draw(
-    ctx: &CanvasRenderingContext2d,
-	json: &str,
-    width: u32,
-    height: u32,
-	num_samples: u32,
-	max_bounces: u32,
-) -> Result<(), JsValue> {
-	let mut scene = serde_json::from_str::<Scene>(&json).expect("Unable to parse scene json!");
-	scene.camera.aspect = width as f64 / height as f64;
-	
-	let mut data = Vec::new();
-	let mut rng = rand::thread_rng();
-	let Scene { ref camera, ref lights, ref objects } = scene;
-	for y in 0..height {
-        for x in 0..width {
-			let u = x as f64 / (width - 1) as f64;
-			let v = (height - y - 1) as f64 / (height - 1) as f64;
+impl From<Scene> for SceneFormat {
+	fn from(v: Scene) -> SceneFormat {
+		SceneFormat {
+			camera: v.camera.into(),
+			lights: v.lights,
+			objects: v.objects.iter().map(|object| object.clone().into()).collect(),
+			environment: v.environment.map(|environment| environment.into()),
+			textures: v.textures.into_iter().map(|(name, texture)| (name, (*texture).clone().into())).collect(),
+			materials: v.materials.into_iter().map(|(name, material)| (name, Arc::unwrap_or_clone(material).into())).collect(),
+		}
+	}
+}
+
+#[allow(non_snake_case)]
+fn reflect(I: Vector3<f64>, N: Vector3<f64>) -> Vector3<f64> {
+	I - N * cgmath::dot(N, I) * 2.0f64
+}
+
+/// Snell's law for a unit direction `I` hitting a surface with unit normal `N`
+/// facing against it, where `eta` is the ratio of the indices of refraction.
+/// Returns `None` on total internal reflection.
+#[allow(non_snake_case)]
+fn refract(I: Vector3<f64>, N: Vector3<f64>, eta: f64) -> Option<Vector3<f64>> {
+	let cos_i = -cgmath::dot(N, I);
+	let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+	if sin2_t >= 1.0 { return None; }
+	let cos_t = (1.0 - sin2_t).sqrt();
+	Some(I * eta + N * (eta * cos_i - cos_t))
+}
+
+/// Unpolarized Fresnel reflectance of a dielectric interface, see `refract` for `eta`.
+fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+	let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+	if sin2_t >= 1.0 { return 1.0; }
+	let cos_t = (1.0 - sin2_t).sqrt();
+	let r_s = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+	let r_p = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+	(r_s * r_s + r_p * r_p) / 2.0
+}
+
+/// Unpolarized Fresnel reflectance of a conductor with complex index of
+/// refraction `eta + i k`, seen from a medium with index 1.
+fn fresnel_conductor(cos_i: f64, eta: f64, k: f64) -> f64 {
+	let cos2 = cos_i.clamp(0.0, 1.0).powi(2);
+	let sin2 = 1.0 - cos2;
+	let t0 = eta * eta - k * k - sin2;
+	let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+	let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+	let t1 = a2_plus_b2 + cos2;
+	let t2 = 2.0 * cos_i.clamp(0.0, 1.0) * a;
+	let r_s = (t1 - t2) / (t1 + t2);
+	let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+	let t4 = t2 * sin2;
+	let r_p = r_s * (t3 - t4) / (t3 + t4);
+	(r_s + r_p) / 2.0
+}
+
+/// Number of shadow rays `DebugShadows` sends toward each area light.
+const DEBUG_SHADOW_SAMPLES: u32 = 16;
+
+/// Whether anything blocks the path from `position` to the sampled point on a
+/// light at `time`.
+fn occluded(objects: &Objects, position: Point3<f64>, time: f64, sample: &LightSample) -> bool {
+	let mut shadow_ray = Ray {
+		origin: position,
+		direction: sample.direction,
+		spread: 0.0,
+		time,
+	};
+	shadow_ray.origin = shadow_ray.at(0.0001);
+	objects.intersect(&shadow_ray).is_some_and(|shadow_hit| shadow_hit.distance <= sample.distance)
+}
+
+/// Shades a reflection or refraction of `ray` leaving `origin`, nudged off the
+/// surface. The new ray keeps the `spread` and `time` of `ray`.
+fn shade_secondary_ray<R: Rng>(scene: &Scene, ray: &Ray, origin: Point3<f64>, direction: Vector3<f64>, bounces_remaining: u32, rng: &mut R) -> Option<Vector3<f64>> {
+	let mut secondary_ray = Ray {
+		origin,
+		direction,
+		spread: ray.spread,
+		time: ray.time,
+	};
+	secondary_ray.origin = secondary_ray.at(0.0001);
+	shade_ray(scene, &secondary_ray, bounces_remaining, rng)
+}
+
+/// Whitted style shading. Area lights are sampled once per light, so their
+/// penumbrae converge as more samples per pixel are averaged. Rays that miss
+/// everything see the scene environment, if there is one.
+pub fn shade_ray<R: Rng>(scene: &Scene, ray: &Ray, bounces_remaining: u32, rng: &mut R) -> Option<Vector3<f64>> {
+	match scene.objects.intersect(ray) {
+		Some(hit) => shade_hit(scene, ray, hit, bounces_remaining, rng),
+		None => scene.environment.as_ref().map(|environment| environment.radiance(ray.direction)),
+	}
+}
+
+/// Color `ray` sees at `hit`. Blended materials shade each of the materials
+/// they blend and add up the results.
+fn shade_hit<R: Rng>(scene: &Scene, ray: &Ray, mut hit: Hit, bounces_remaining: u32, rng: &mut R) -> Option<Vector3<f64>> {
+	let Scene { ref objects, ref lights, .. } = *scene;
+	apply_modifiers(ray, &mut hit);
+	let footprint = texture_footprint(ray, &hit);
+	match *hit.material {
+		Material::DebugPosition => {
+			Some(Vector3::new(hit.position.x, hit.position.y, hit.position.z))
+		}
+		Material::DebugNormals => {
+			Some(hit.normal)
+		}
+		Material::DebugShadows => {
+			// Red faces away from a light, green is shadowed and blue is lit. Area
+			// lights report the fraction of their samples in each state.
+			let mut ray_color = Vector3::new(0.0, 0.0, 0.0);
+			
+			for light in lights {
+				let samples = if light.is_area() { DEBUG_SHADOW_SAMPLES } else { 1 };
+				let mut light_color = Vector3::new(0.0, 0.0, 0.0);
+				for _ in 0..samples {
+					let sample = light.sample(hit.position, rng);
+					light_color += if cgmath::dot(hit.normal, sample.direction) < 0.0 {
+						Vector3::new(1.0, 0.0, 0.0)
+					} else if occluded(objects, hit.position, ray.time, &sample) {
+						Vector3::new(0.0, 1.0, 0.0)
+					} else {
+						Vector3::new(0.0, 0.0, 1.0)
+					};
+				}
+				ray_color += light_color / samples as f64;
+			}
+			Some(ray_color / lights.len() as f64)
+		}
+		Material::Emissive { color } => {
+			Some(color)
+		}
+		Material::Mirror => {
+			if bounces_remaining == 0 { return None; }
+			let direction = reflect(ray.direction, hit.normal).normalize();
+			shade_secondary_ray(scene, ray, hit.position, direction, bounces_remaining-1, rng)
+		}
+		Material::Dielectric { ior, tint } => {
+			if bounces_remaining == 0 { return None; }
+			let direction = ray.direction.normalize();
+			let entering = cgmath::dot(direction, hit.normal) < 0.0;
+			let (normal, eta) = if entering { (hit.normal, 1.0 / ior) } else { (-hit.normal, ior) };
+			let cos_i = (-cgmath::dot(direction, normal)).min(1.0);
+			let reflectance = fresnel_dielectric(cos_i, eta);
 			
-			let mut pixel_color = Vector3::new(0.0, 0.0, 0.0);
-			for i in 0..num_samples {
-				let du: f64 = rng.gen_range(-0.5..=0.5) / (width - 1) as f64;
-				let dv: f64 = rng.gen_range(-0.5..=0.5) / (height - 1) as f64;
+			let mut ray_color = Vector3::new(0.0, 0.0, 0.0);
+			let reflection = shade_secondary_ray(scene, ray, hit.position, reflect(direction, normal), bounces_remaining-1, rng);
+			if let Some(reflection) = reflection {
+				ray_color += reflection * reflectance;
+			}
+			if let Some(refracted) = refract(direction, normal, eta) {
+				let refraction = shade_secondary_ray(scene, ray, hit.position, refracted, bounces_remaining-1, rng);
+				if let Some(refraction) = refraction {
+					ray_color += refraction.mul_element_wise(tint) * (1.0 - reflectance);
+				}
+			}
+			Some(ray_color)
+		}
+		Material::BlinnPhong { ref ambient, ref diffuse, ref specular, intensity } => {
+			let ambient = ambient.evaluate(hit.uv, hit.position, footprint);
+			let diffuse = diffuse.evaluate(hit.uv, hit.position, footprint);
+			let specular = specular.evaluate(hit.uv, hit.position, footprint);
+			let mut ray_color = ambient;
+	
+			for light in lights {
+				let sample = light.sample(hit.position, rng);
+				if cgmath::dot(hit.normal, sample.direction) < 0.0 { continue; }
+				if occluded(objects, hit.position, ray.time, &sample) { continue; }
 				
-				let ray = if i == 0 { camera.get_ray(u,v) } else { camera.get_ray(u + du,v + dv) };
-				if let Some(ray_color) = shade_ray(&objects, &lights, &camera, &ray, max_bounces) {
-					pixel_color += ray_color;
+				ray_color += {
+					let p_nor = hit.normal.normalize();
+					let p_eye = (ray.origin - hit.position).normalize();
+					let p_light = sample.direction;
+					let p_half = (p_light + p_eye).normalize();
+					let cd = diffuse * 0.0_f64.max(cgmath::dot(p_light, p_nor));
+					let cs = specular * 0.0_f64.max(cgmath::dot(p_half, p_nor)).powf(intensity);
+					sample.radiance.mul_element_wise(cd + cs)
 				}
 			}
-			let pixel_color = pixel_color.map(|v| (v / num_samples as f64)).map(|v| 255.0 * v.clamp(0.0, 1.0)).map(|v| v as u8);
-			data.push(pixel_color.x);
-			data.push(pixel_color.y);
-			data.push(pixel_color.z);
-			data.push(255u8);
+			
+			Some(ray_color)
+		}
+		Material::Glossy { ref tint, roughness, metal } => {
+			if bounces_remaining == 0 { return None; }
+			let glossy = microfacet::Glossy::evaluate(tint, roughness, metal, &hit, footprint);
+			let wo = -ray.direction.normalize();
+			let n = if cgmath::dot(hit.normal, wo) < 0.0 { -hit.normal } else { hit.normal };
+			// One reflection drawn from the lobe, so the blur resolves as samples accumulate.
+			let (direction, weight) = if glossy.is_smooth() {
+				(reflect(-wo, n), glossy.fresnel(cgmath::dot(wo, n)))
+			} else {
+				let wi = glossy.sample(n, wo, rng);
+				let pdf = glossy.pdf(n, wo, wi);
+				if pdf <= 0.0 { return Some(Vector3::new(0.0, 0.0, 0.0)); }
+				(wi, glossy.eval(n, wo, wi) * (cgmath::dot(n, wi) / pdf))
+			};
+			let reflection = shade_secondary_ray(scene, ray, hit.position, direction, bounces_remaining-1, rng);
+			reflection.map(|reflection| reflection.mul_element_wise(weight))
+		}
+		Material::Pbr { ref base_color, ref metallic, ref roughness, ref ior } => {
+			let brdf = microfacet::Microfacet::evaluate(base_color, metallic, roughness, ior, &hit, footprint);
+			let wo = -ray.direction.normalize();
+			let n = if cgmath::dot(hit.normal, wo) < 0.0 { -hit.normal } else { hit.normal };
+			let mut ray_color = Vector3::new(0.0, 0.0, 0.0);
+
+			for light in lights {
+				let sample = light.sample(hit.position, rng);
+				let cos_theta = cgmath::dot(n, sample.direction);
+				if cos_theta <= 0.0 { continue; }
+				if occluded(objects, hit.position, ray.time, &sample) { continue; }
+				// Scaled by PI like in `trace_path`, so a white Lambertian base
+				// matches a white `BlinnPhong` diffuse.
+				ray_color += (sample.radiance * std::f64::consts::PI).mul_element_wise(brdf.eval(n, wo, sample.direction)) * cos_theta;
+			}
+
+			Some(ray_color)
+		}
+		Material::Mix { ref a, ref b, ref factor } => {
+			let factor = factor.evaluate(ray, &hit, footprint);
+			let a = if factor < 1.0 { shade_hit(scene, ray, hit.with_material(a), bounces_remaining, rng) } else { None };
+			let b = if factor > 0.0 { shade_hit(scene, ray, hit.with_material(b), bounces_remaining, rng) } else { None };
+			blend(a, 1.0 - factor, b, factor)
 		}
+		Material::Layered { ref base, ref coat, ref coat_weight } => {
+			let coat_weight = coat_weight.evaluate(ray, &hit, footprint);
+			let base = if coat_weight < 1.0 { shade_hit(scene, ray, hit.with_material(base), bounces_remaining, rng) } else { None };
+			let coat = if coat_weight > 0.0 { shade_hit(scene, ray, hit.with_material(coat), bounces_remaining, rng) } else { None };
+			blend(base, (1.0 - coat_weight).powi(2), coat, coat_weight)
+		}
+		Material::Named { .. } | Material::Cutout { .. } | Material::NormalMap { .. } | Material::Bump { .. } => unreachable!("removed by apply_modifiers"),
 	}
-    let data = ImageData::new_with_u8_clamped_array_and_sh(Clamped(&data), width, height)?;
-    ctx.put_image_data(&data, 0.0, 0.0)
 }
 
-#[wasm_bindgen(start)]
-fn main() -> Result<(), JsValue> {
-	panic::set_hook(Box::new(console_error_panic_hook::hook));
-	Ok(())
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+	use cgmath::{Point3, Vector3};
+	use rand::{SeedableRng, rngs::StdRng};
+	use serde_json::json;
+	use crate::{Scene, SceneError, Ray, shade_ray, trace_path};
+	use crate::testing::{load_scene, sphere, expect_error};
+
+	#[test]
+	fn json() {
+		let error = expect_error(Scene::from_json("{\n  \"camera\": [1,\n"), "invalid scene json at line 3 column 0: EOF while parsing a value");
+		assert!(matches!(error, SceneError::Json { line: 3, column: 0, .. }));
+	}
+
+	#[test]
+	fn unknown_object_and_light_types() {
+		let error = expect_error(load_scene(json!({ "objects": [{ "type": "Cube" }] })),
+			"objects[0]: unknown object type \"Cube\", expected one of Plane, Sphere, Ellipsoid, Mesh");
+		assert_eq!(error, SceneError::UnknownObjectType { index: 0, type_name: String::from("Cube") });
+		let error = expect_error(load_scene(json!({ "lights": [{ "type": "Laser" }] })),
+			"lights[0]: unknown light type \"Laser\", expected one of Point, Directional, Spot, Rect, Disk, Sphere");
+		assert_eq!(error, SceneError::UnknownLightType { index: 0, type_name: String::from("Laser") });
+	}
+
+	#[test]
+	fn missing_field() {
+		let scene = json!({ "camera": { "origin": { "x": 0, "y": 0, "z": 5 } }, "lights": [], "objects": [] });
+		let error = expect_error(Scene::from_json(&scene.to_string()), "missing field \"fovy\"");
+		assert!(matches!(error, SceneError::MissingField { ref field, .. } if field == "fovy"));
+	}
+
+	#[test]
+	fn layered_base_is_seen_through_the_coat() {
+		let emissive = |v: f64| json!({ "Emissive": { "color": { "r": v, "g": v, "b": v } } });
+		let ray = Ray { origin: Point3::new(0.0, 0.0, 5.0), direction: Vector3::new(0.0, 0.0, -1.0), spread: 0.0, time: 0.0 };
+		let mut rng = StdRng::seed_from_u64(1);
+		// A black coat reflecting half the light leaves a quarter of the base,
+		// where a mix would show half of it.
+		let layered = json!({ "Layered": { "base": emissive(1.0), "coat": emissive(0.0), "coat_weight": 0.5 } });
+		let mix = json!({ "Mix": { "a": emissive(1.0), "b": emissive(0.0), "factor": 0.5 } });
+		for (material, expected) in [(layered, 0.25), (mix, 0.5)] {
+			let scene = load_scene(json!({ "objects": [sphere(material.clone())] })).unwrap();
+			assert_eq!(shade_ray(&scene, &ray, 1, &mut rng).unwrap().x, expected, "{}", material);
+			let samples = 4000;
+			let traced = (0..samples).map(|_| trace_path(&scene, &ray, 1, &mut rng).x).sum::<f64>() / samples as f64;
+			assert!((traced - expected).abs() < 0.03, "{} traced {}", material, traced);
+		}
+	}
+}
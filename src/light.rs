@@ -1,42 +1,362 @@
-use serde::{Serialize, Deserialize};
-use cgmath::{Vector3, Point3};
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de;
+use cgmath::{Vector3, Point3, Rad};
+use cgmath::{InnerSpace, MetricSpace};
+use rand::Rng;
 use crate::{VectorFormat, RgbFormat};
+use crate::sampling::{orthonormal_basis, uniform_disk, uniform_sphere};
 
+/// A light source. `Directional` lights are infinitely far away, like the sun,
+/// and `Spot` lights fade out smoothly between their inner and outer cone
+/// angles, measured from `direction` in radians.
+///
+/// Area lights spread their intensity evenly over their surface and are sampled
+/// at a random point for every shadow ray, so shadows soften as samples
+/// accumulate. `Rect` and `Disk` lights emit from both faces with a cosine
+/// falloff.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(from = "LightFormat", into = "LightFormat")]
-pub struct Light {
-	pub position: Point3<f64>,
-	pub color: Vector3<f64>,
-	pub intensity: f64,
-	pub attenuation: Option<(f64, f64, f64)>,
+#[serde(from = "PointByDefault", into = "LightFormat")]
+pub enum Light {
+	Point {
+		position: Point3<f64>,
+		color: Vector3<f64>,
+		intensity: f64,
+		attenuation: Option<(f64, f64, f64)>,
+	},
+	/// Light shining along `direction` from infinitely far away.
+	Directional {
+		direction: Vector3<f64>,
+		color: Vector3<f64>,
+		intensity: f64,
+	},
+	Spot {
+		position: Point3<f64>,
+		direction: Vector3<f64>,
+		inner_angle: Rad<f64>,
+		outer_angle: Rad<f64>,
+		color: Vector3<f64>,
+		intensity: f64,
+		attenuation: Option<(f64, f64, f64)>,
+	},
+	/// Parallelogram centered on `position` spanned by the full edge vectors
+	/// `edge_u` and `edge_v`.
+	Rect {
+		position: Point3<f64>,
+		edge_u: Vector3<f64>,
+		edge_v: Vector3<f64>,
+		color: Vector3<f64>,
+		intensity: f64,
+		attenuation: Option<(f64, f64, f64)>,
+	},
+	Disk {
+		position: Point3<f64>,
+		normal: Vector3<f64>,
+		radius: f64,
+		color: Vector3<f64>,
+		intensity: f64,
+		attenuation: Option<(f64, f64, f64)>,
+	},
+	Sphere {
+		position: Point3<f64>,
+		radius: f64,
+		color: Vector3<f64>,
+		intensity: f64,
+		attenuation: Option<(f64, f64, f64)>,
+	},
+}
+
+/// Light arriving at a shading point from one sample of a light.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LightSample {
+	/// Unit vector from the shading point toward the light.
+	pub direction: Vector3<f64>,
+	/// Infinite for directional lights.
+	pub distance: f64,
+	pub radiance: Vector3<f64>,
+}
+
+fn attenuate(attenuation: Option<(f64, f64, f64)>, r: f64) -> f64 {
+	let (constant, linear, quadratic) = attenuation.unwrap_or((1.0, 0.0, 0.0));
+	1.0 / (constant + (linear*r) + (quadratic*r*r))
+}
+
+/// Smooth falloff from 1 inside the inner cone to 0 outside the outer cone.
+fn spot_falloff(cos_theta: f64, inner_angle: Rad<f64>, outer_angle: Rad<f64>) -> f64 {
+	let (cos_inner, cos_outer) = (inner_angle.0.cos(), outer_angle.0.cos());
+	if cos_theta >= cos_inner { return 1.0; }
+	if cos_theta <= cos_outer { return 0.0; }
+	let t = (cos_theta - cos_outer) / (cos_inner - cos_outer);
+	t * t * (3.0 - 2.0 * t)
+}
+
+impl Light {
+	/// Whether the light has a surface, and so casts soft shadows.
+	pub fn is_area(&self) -> bool {
+		matches!(self, Light::Rect { .. } | Light::Disk { .. } | Light::Sphere { .. })
+	}
+
+	/// Checks that the light's direction, or the normal of its surface, isn't
+	/// zero, which would make every sample NaN.
+	pub fn validate(&self) -> Result<(), String> {
+		match *self {
+			Light::Directional { direction, .. } | Light::Spot { direction, .. } if direction.magnitude2() < 1e-12 => Err(String::from("direction is zero")),
+			Light::Rect { edge_u, edge_v, .. } if edge_u.cross(edge_v).magnitude2() < 1e-12 => Err(String::from("edge_u and edge_v are zero or parallel")),
+			Light::Disk { normal, .. } if normal.magnitude2() < 1e-12 => Err(String::from("normal is zero")),
+			_ => Ok(()),
+		}
+	}
+
+	/// Picks a point on the light and returns the light it sends toward `point`.
+	/// Only area lights draw from `rng`.
+	pub fn sample<R: Rng>(&self, point: Point3<f64>, rng: &mut R) -> LightSample {
+		let (sample_position, normal, color, intensity, attenuation) = match *self {
+			Light::Point { position, color, intensity, attenuation } => (position, None, color, intensity, attenuation),
+			Light::Directional { direction, color, intensity } => {
+				return LightSample {
+					direction: -direction.normalize(),
+					distance: f64::INFINITY,
+					radiance: color * intensity,
+				};
+			}
+			Light::Spot { position, direction, inner_angle, outer_angle, color, intensity, attenuation } => {
+				let cos_theta = cgmath::dot((point - position).normalize(), direction.normalize());
+				let intensity = intensity * spot_falloff(cos_theta, inner_angle, outer_angle);
+				(position, None, color, intensity, attenuation)
+			}
+			Light::Rect { position, edge_u, edge_v, color, intensity, attenuation } => {
+				let (u, v): (f64, f64) = (rng.gen(), rng.gen());
+				let sample_position = position + edge_u * (u - 0.5) + edge_v * (v - 0.5);
+				(sample_position, Some(edge_u.cross(edge_v).normalize()), color, intensity, attenuation)
+			}
+			Light::Disk { position, normal, radius, color, intensity, attenuation } => {
+				let normal = normal.normalize();
+				let (t, b) = orthonormal_basis(normal);
+				let (x, y) = uniform_disk(rng);
+				(position + (t * x + b * y) * radius, Some(normal), color, intensity, attenuation)
+			}
+			Light::Sphere { position, radius, color, intensity, attenuation } => {
+				// Only the hemisphere facing the shading point can be seen from it.
+				let mut offset = uniform_sphere(rng);
+				if cgmath::dot(offset, point - position) < 0.0 { offset = -offset; }
+				(position + offset * radius, None, color, intensity, attenuation)
+			}
+		};
+
+		let distance = sample_position.distance(point);
+		let direction = (sample_position - point).normalize();
+		let cos_light = normal.map_or(1.0, |normal| cgmath::dot(normal, direction).abs());
+		LightSample {
+			direction,
+			distance,
+			radiance: color * intensity * attenuate(attenuation, distance) * cos_light,
+		}
+	}
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct LightFormat {
-	pub position: VectorFormat,
-	pub color: RgbFormat,
-	pub intensity: f64,
-	pub attenuation: Option<(f64, f64, f64)>,
+#[serde(tag = "type")]
+pub enum LightFormat {
+	Point {
+		position: VectorFormat,
+		color: RgbFormat,
+		intensity: f64,
+		attenuation: Option<(f64, f64, f64)>,
+	},
+	Directional {
+		direction: VectorFormat,
+		color: RgbFormat,
+		intensity: f64,
+	},
+	Spot {
+		position: VectorFormat,
+		direction: VectorFormat,
+		inner_angle: f64,
+		outer_angle: f64,
+		color: RgbFormat,
+		intensity: f64,
+		attenuation: Option<(f64, f64, f64)>,
+	},
+	Rect {
+		position: VectorFormat,
+		edge_u: VectorFormat,
+		edge_v: VectorFormat,
+		color: RgbFormat,
+		intensity: f64,
+		attenuation: Option<(f64, f64, f64)>,
+	},
+	Disk {
+		position: VectorFormat,
+		normal: VectorFormat,
+		radius: f64,
+		color: RgbFormat,
+		intensity: f64,
+		attenuation: Option<(f64, f64, f64)>,
+	},
+	Sphere {
+		position: VectorFormat,
+		radius: f64,
+		color: RgbFormat,
+		intensity: f64,
+		attenuation: Option<(f64, f64, f64)>,
+	},
+}
+
+/// Reads lights without a `type` as `Point` lights, the only kind there was
+/// before the tag existed.
+struct PointByDefault(LightFormat);
+
+impl<'de> Deserialize<'de> for PointByDefault {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PointByDefault, D::Error> {
+		let mut value = serde_json::Value::deserialize(deserializer)?;
+		if let Some(light) = value.as_object_mut() {
+			light.entry("type").or_insert_with(|| "Point".into());
+		}
+		LightFormat::deserialize(value).map(PointByDefault).map_err(de::Error::custom)
+	}
+}
+
+impl From<PointByDefault> for Light {
+	fn from(v: PointByDefault) -> Light {
+		v.0.into()
+	}
 }
 
 impl From<LightFormat> for Light {
-    fn from(v: LightFormat) -> Light { 
-        Light {
-            position: v.position.into(),
-            color: v.color.into(),
-            intensity: v.intensity,
-			attenuation: v.attenuation,
-        }
-    }
+	fn from(v: LightFormat) -> Light {
+		match v {
+			LightFormat::Point { position, color, intensity, attenuation } => Light::Point {
+				position: position.into(),
+				color: color.into(),
+				intensity,
+				attenuation,
+			},
+			LightFormat::Directional { direction, color, intensity } => Light::Directional {
+				direction: direction.into(),
+				color: color.into(),
+				intensity,
+			},
+			LightFormat::Spot { position, direction, inner_angle, outer_angle, color, intensity, attenuation } => Light::Spot {
+				position: position.into(),
+				direction: direction.into(),
+				inner_angle: Rad(inner_angle),
+				outer_angle: Rad(outer_angle),
+				color: color.into(),
+				intensity,
+				attenuation,
+			},
+			LightFormat::Rect { position, edge_u, edge_v, color, intensity, attenuation } => Light::Rect {
+				position: position.into(),
+				edge_u: edge_u.into(),
+				edge_v: edge_v.into(),
+				color: color.into(),
+				intensity,
+				attenuation,
+			},
+			LightFormat::Disk { position, normal, radius, color, intensity, attenuation } => Light::Disk {
+				position: position.into(),
+				normal: normal.into(),
+				radius,
+				color: color.into(),
+				intensity,
+				attenuation,
+			},
+			LightFormat::Sphere { position, radius, color, intensity, attenuation } => Light::Sphere {
+				position: position.into(),
+				radius,
+				color: color.into(),
+				intensity,
+				attenuation,
+			},
+		}
+	}
 }
 
 impl From<Light> for LightFormat {
-    fn from(v: Light) -> LightFormat { 
-        LightFormat {
-            position: v.position.into(),
-            color: v.color.into(),
-            intensity: v.intensity,
-			attenuation: v.attenuation,
-        }
-    }
-}
\ No newline at end of file
+	fn from(v: Light) -> LightFormat {
+		match v {
+			Light::Point { position, color, intensity, attenuation } => LightFormat::Point {
+				position: position.into(),
+				color: color.into(),
+				intensity,
+				attenuation,
+			},
+			Light::Directional { direction, color, intensity } => LightFormat::Directional {
+				direction: direction.into(),
+				color: color.into(),
+				intensity,
+			},
+			Light::Spot { position, direction, inner_angle, outer_angle, color, intensity, attenuation } => LightFormat::Spot {
+				position: position.into(),
+				direction: direction.into(),
+				inner_angle: inner_angle.0,
+				outer_angle: outer_angle.0,
+				color: color.into(),
+				intensity,
+				attenuation,
+			},
+			Light::Rect { position, edge_u, edge_v, color, intensity, attenuation } => LightFormat::Rect {
+				position: position.into(),
+				edge_u: edge_u.into(),
+				edge_v: edge_v.into(),
+				color: color.into(),
+				intensity,
+				attenuation,
+			},
+			Light::Disk { position, normal, radius, color, intensity, attenuation } => LightFormat::Disk {
+				position: position.into(),
+				normal: normal.into(),
+				radius,
+				color: color.into(),
+				intensity,
+				attenuation,
+			},
+			Light::Sphere { position, radius, color, intensity, attenuation } => LightFormat::Sphere {
+				position: position.into(),
+				radius,
+				color: color.into(),
+				intensity,
+				attenuation,
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{Scene, SceneError};
+
+	fn scene(light: &str) -> String {
+		format!(r#"{{ "camera": {{ "origin": {{ "x": 0, "y": 0, "z": 5 }}, "fovy": 0.8 }}, "lights": [{}], "objects": [] }}"#, light)
+	}
+
+	#[test]
+	fn lights_without_type_are_point_lights() {
+		let light = r#""position": { "x": 1, "y": 2, "z": 3 }, "color": { "r": 1, "g": 1, "b": 1 }, "intensity": 2"#;
+		let untyped = Scene::from_json(&scene(&format!("{{ {} }}", light))).unwrap();
+		let typed = Scene::from_json(&scene(&format!(r#"{{ "type": "Point", {} }}"#, light))).unwrap();
+		assert_eq!(format!("{:?}", untyped.lights), format!("{:?}", typed.lights));
+		assert!(matches!(untyped.lights[0], super::Light::Point { intensity, .. } if intensity == 2.0));
+
+		let error = Scene::from_json(&scene(r#"{ "position": { "x": 1, "y": 2, "z": 3 }, "intensity": 2 }"#)).err().unwrap();
+		assert!(matches!(error, SceneError::MissingField { ref field, .. } if field == "color"), "{}", error);
+	}
+
+	#[test]
+	fn rejects_lights_without_a_direction() {
+		let area = r#""position": { "x": 0, "y": 2, "z": 0 }, "color": { "r": 1, "g": 1, "b": 1 }, "intensity": 2"#;
+		let invalid = [
+			(format!(r#"{{ "type": "Rect", {}, "edge_u": {{ "x": 1, "y": 0, "z": 0 }}, "edge_v": {{ "x": 2, "y": 0, "z": 0 }} }}"#, area), "edge_u and edge_v are zero or parallel"),
+			(format!(r#"{{ "type": "Rect", {}, "edge_u": {{ "x": 0, "y": 0, "z": 0 }}, "edge_v": {{ "x": 0, "y": 0, "z": 1 }} }}"#, area), "edge_u and edge_v are zero or parallel"),
+			(format!(r#"{{ "type": "Disk", {}, "normal": {{ "x": 0, "y": 0, "z": 0 }}, "radius": 1 }}"#, area), "normal is zero"),
+			(String::from(r#"{ "type": "Directional", "direction": { "x": 0, "y": 0, "z": 0 }, "color": { "r": 1, "g": 1, "b": 1 }, "intensity": 1 }"#), "direction is zero"),
+			(format!(r#"{{ "type": "Spot", {}, "direction": {{ "x": 0, "y": 0, "z": 0 }}, "inner_angle": 0.2, "outer_angle": 0.4 }}"#, area), "direction is zero"),
+		];
+		for (light, message) in invalid {
+			let error = Scene::from_json(&scene(&light)).err().unwrap();
+			assert_eq!(error, SceneError::InvalidLight { index: 0, message: String::from(message) });
+			assert_eq!(error.to_string(), format!("lights[0]: {}", message));
+		}
+		let rect = format!(r#"{{ "type": "Rect", {}, "edge_u": {{ "x": 1, "y": 0, "z": 0 }}, "edge_v": {{ "x": 0, "y": 0, "z": 1 }} }}"#, area);
+		assert!(Scene::from_json(&scene(&rect)).is_ok());
+	}
+}
@@ -1,9 +1,14 @@
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::{self, Visitor, MapAccess, IntoDeserializer, value::MapAccessDeserializer};
+use std::fmt;
 use cgmath::Vector3;
-use crate::RgbFormat;
+use cgmath::InnerSpace;
+use std::path::Path;
+use std::sync::Arc;
+use crate::{Ray, Hit, RgbFormat, Texture, TextureFormat, LoadContext, SceneError, fresnel_conductor, fresnel_dielectric};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(from = "MaterialFormat", into = "MaterialFormat")]
+#[serde(try_from = "MaterialFormat", into = "MaterialFormat")]
 pub enum Material {
 	DebugPosition,
 	DebugNormals,
@@ -12,16 +17,205 @@ pub enum Material {
 		color: Vector3<f64>,
 	},
 	Mirror,
+	/// Glass-like material that reflects and refracts according to the Fresnel
+	/// equations. `tint` filters the transmitted light.
+	Dielectric {
+		ior: f64,
+		tint: Vector3<f64>,
+	},
+	/// Phong lighting with the halfway vector. Each color may be a texture.
 	BlinnPhong {
-		ambient: Vector3<f64>,
-		diffuse: Vector3<f64>,
-		specular: Vector3<f64>,
+		ambient: Texture,
+		diffuse: Texture,
+		specular: Texture,
 		intensity: f64,
+	},
+	/// Mirror blurred by a GGX lobe `roughness` wide, reflecting `tint`, times
+	/// the Fresnel reflectance of `metal` if set.
+	Glossy {
+		tint: Texture,
+		roughness: f64,
+		metal: Option<Metal>,
+	},
+	/// Metallic-roughness material as in glTF, with a GGX microfacet specular
+	/// layer. Dielectrics reflect according to `ior` and show `base_color`
+	/// underneath, metals reflect tinted by `base_color`. `metallic`, `roughness`
+	/// and `ior` are read from the red channel of their textures.
+	Pbr {
+		base_color: Texture,
+		metallic: Texture,
+		roughness: Texture,
+		ior: Texture,
+	},
+	/// Bends the shading normal of `material` with a tangent space normal map,
+	/// whose red, green and blue hold the normal along the tangent, bitangent and
+	/// surface normal. `strength` scales the tilt.
+	NormalMap {
+		material: Arc<Material>,
+		map: Texture,
+		strength: f64,
+	},
+	/// Bends the shading normal of `material` as if the surface were pushed out
+	/// along it by the brightness of `map` times `depth` world units.
+	Bump {
+		material: Arc<Material>,
+		map: Texture,
+		depth: f64,
+	},
+	/// `material` with the parts where `opacity`, read from the red channel, is
+	/// zero cut away. Rays, shadow rays included, pass through those parts, and
+	/// through fractional ones at random with a chance of one minus the opacity.
+	/// Blends can't contain one, wrap the blend instead.
+	Cutout {
+		material: Arc<Material>,
+		opacity: Texture,
+	},
+	/// One of the scene's named materials, shared by every object using it.
+	Named {
+		name: String,
+		material: Arc<Material>,
+	},
+	/// Blend of `a` and `b`, showing `b` by `factor` and `a` by the rest.
+	Mix {
+		a: Arc<Material>,
+		b: Arc<Material>,
+		factor: Factor,
+	},
+	/// `coat` on top of `base`. The coat reflects `coat_weight` of the light and
+	/// lets the rest through to the base, whose light loses `coat_weight` again
+	/// on its way back out, so the base shows by `(1 - coat_weight)²`. Light the
+	/// coat reflects back down onto the base is lost. A Fresnel weight gives a
+	/// clear coat that shines toward grazing angles.
+	Layered {
+		base: Arc<Material>,
+		coat: Arc<Material>,
+		coat_weight: Factor,
+	},
+}
+
+impl Material {
+	/// Whether `predicate` holds for this material or any material it wraps or blends.
+	pub fn any<F: Fn(&Material) -> bool>(&self, predicate: &F) -> bool {
+		predicate(self) || match self {
+			Material::Named { material, .. } | Material::NormalMap { material, .. } | Material::Bump { material, .. } | Material::Cutout { material, .. } => material.any(predicate),
+			Material::Mix { a, b, .. } | Material::Layered { base: a, coat: b, .. } => a.any(predicate) || b.any(predicate),
+			_ => false,
+		}
+	}
+}
+
+/// Weight of a blended material, between 0 and 1.
+#[derive(Debug, Clone)]
+pub enum Factor {
+	/// Read from the red channel, so a number works too.
+	Texture(Texture),
+	/// Reflectance of a dielectric with index of refraction `ior` at the angle
+	/// the surface is seen at.
+	Fresnel {
+		ior: f64,
+	},
+}
+
+impl Factor {
+	pub fn evaluate(&self, ray: &Ray, hit: &Hit, footprint: f64) -> f64 {
+		match *self {
+			Factor::Texture(ref texture) => texture.evaluate(hit.uv, hit.position, footprint).x.clamp(0.0, 1.0),
+			Factor::Fresnel { ior } => {
+				let cos_theta = cgmath::dot(ray.direction.normalize(), hit.normal).abs().min(1.0);
+				fresnel_dielectric(cos_theta, 1.0 / ior)
+			}
+		}
+	}
+}
+
+/// A number, a texture, or `{"type": "Fresnel", "ior": ...}`.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum FactorFormat {
+	Fresnel(FresnelFormat),
+	Texture(TextureFormat),
+}
+
+impl<'de> Deserialize<'de> for FactorFormat {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<FactorFormat, D::Error> {
+		let value = serde_json::Value::deserialize(deserializer)?;
+		let factor = match value.get("type").and_then(|type_name| type_name.as_str()) {
+			Some("Fresnel") => FresnelFormat::deserialize(value).map(FactorFormat::Fresnel),
+			_ => TextureFormat::deserialize(value).map(FactorFormat::Texture),
+		};
+		factor.map_err(de::Error::custom)
 	}
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum FresnelFormat {
+	Fresnel {
+		#[serde(default = "default_fresnel_ior")]
+		ior: f64,
+	},
+}
+
+fn default_fresnel_ior() -> f64 {
+	1.5
+}
+
+impl FactorFormat {
+	pub fn load(self, context: &LoadContext) -> Result<Factor, SceneError> {
+		Ok(match self {
+			FactorFormat::Fresnel(FresnelFormat::Fresnel { ior }) => Factor::Fresnel { ior },
+			FactorFormat::Texture(texture) => Factor::Texture(texture.load(context)?),
+		})
+	}
+}
+
+impl From<Factor> for FactorFormat {
+	fn from(v: Factor) -> FactorFormat {
+		match v {
+			Factor::Texture(texture) => FactorFormat::Texture(texture.into()),
+			Factor::Fresnel { ior } => FactorFormat::Fresnel(FresnelFormat::Fresnel { ior }),
+		}
+	}
+}
+
+/// A material, or the name of one of the scene's named materials. Names of
+/// materials without parameters, like `"Mirror"`, refer to those materials.
+#[derive(Serialize)]
+#[serde(untagged)]
 pub enum MaterialFormat {
+	Material(TaggedMaterialFormat),
+	Named(String),
+}
+
+// Not derived as untagged, which would report any mistake in an inline material
+// as matching no variant instead of saying what is wrong with it.
+impl<'de> Deserialize<'de> for MaterialFormat {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MaterialFormat, D::Error> {
+		struct MaterialVisitor;
+
+		impl<'de> Visitor<'de> for MaterialVisitor {
+			type Value = MaterialFormat;
+
+			fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+				f.write_str("a material or the name of one")
+			}
+
+			fn visit_str<E: de::Error>(self, v: &str) -> Result<MaterialFormat, E> {
+				let material: Result<TaggedMaterialFormat, E> = TaggedMaterialFormat::deserialize(v.into_deserializer());
+				Ok(material.map_or_else(|_| MaterialFormat::Named(v.to_string()), MaterialFormat::Material))
+			}
+
+			fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<MaterialFormat, A::Error> {
+				TaggedMaterialFormat::deserialize(MapAccessDeserializer::new(map)).map(MaterialFormat::Material)
+			}
+		}
+
+		deserializer.deserialize_any(MaterialVisitor)
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum TaggedMaterialFormat {
 	DebugPosition,
 	DebugNormals,
 	DebugShadows,
@@ -29,46 +223,393 @@ pub enum MaterialFormat {
 		color: RgbFormat,
 	},
 	Mirror,
+	Dielectric {
+		ior: f64,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		tint: Option<RgbFormat>,
+	},
 	BlinnPhong {
-		ambient: RgbFormat,
-		diffuse: RgbFormat,
-		specular: RgbFormat,
+		ambient: TextureFormat,
+		diffuse: TextureFormat,
+		specular: TextureFormat,
 		intensity: f64,
+	},
+	Glossy {
+		#[serde(default = "default_tint")]
+		tint: TextureFormat,
+		#[serde(default)]
+		roughness: f64,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		metal: Option<MetalFormat>,
+	},
+	/// Every parameter is a texture, so a number or a color works too.
+	Pbr {
+		base_color: TextureFormat,
+		metallic: TextureFormat,
+		roughness: TextureFormat,
+		#[serde(default = "default_ior")]
+		ior: TextureFormat,
+	},
+	/// The map holds linear data, so its images default to `"srgb": false` and
+	/// named textures used as maps must set it.
+	NormalMap {
+		material: Box<MaterialFormat>,
+		map: TextureFormat,
+		#[serde(default = "default_strength")]
+		strength: f64,
+	},
+	Bump {
+		material: Box<MaterialFormat>,
+		map: TextureFormat,
+		depth: f64,
+	},
+	/// Image masks can read the image's alpha channel with `"alpha": true`.
+	Cutout {
+		material: Box<MaterialFormat>,
+		opacity: TextureFormat,
+	},
+	Mix {
+		a: Box<MaterialFormat>,
+		b: Box<MaterialFormat>,
+		factor: FactorFormat,
+	},
+	Layered {
+		base: Box<MaterialFormat>,
+		coat: Box<MaterialFormat>,
+		coat_weight: FactorFormat,
+	},
+}
+
+fn default_strength() -> f64 {
+	1.0
+}
+
+fn default_tint() -> TextureFormat {
+	TextureFormat::Scalar(1.0)
+}
+
+fn default_ior() -> TextureFormat {
+	TextureFormat::Scalar(1.5)
+}
+
+/// Whether `name` stands for a built-in material, like `"Mirror"`, rather than
+/// one of the scene's named materials.
+pub(crate) fn is_builtin_name(name: &str) -> bool {
+	let material: Result<TaggedMaterialFormat, de::value::Error> = TaggedMaterialFormat::deserialize(name.into_deserializer());
+	material.is_ok()
+}
+
+/// Loads one of the materials a `Mix` or `Layered` blends. Intersection decides
+/// whether a ray passes a cut out surface before shading picks what part of a
+/// blend it sees, so cutouts inside blends are rejected.
+fn load_blended(v: MaterialFormat, context: &LoadContext) -> Result<Arc<Material>, SceneError> {
+	let material = v.load(context)?;
+	if material.any(&|material| matches!(material, Material::Cutout { .. })) {
+		return Err(SceneError::CutoutInBlend);
 	}
+	Ok(Arc::new(material))
 }
 
-impl From<MaterialFormat> for Material {
-    fn from(v: MaterialFormat) -> Material {
-		match v {
-			MaterialFormat::DebugPosition => Material::DebugPosition,
-			MaterialFormat::DebugNormals => Material::DebugNormals,
-			MaterialFormat::DebugShadows => Material::DebugShadows,
-			MaterialFormat::Emissive { color } => Material::Emissive { color: color.into() },
-			MaterialFormat::Mirror => Material::Mirror,
-			MaterialFormat::BlinnPhong { ambient, diffuse, specular, intensity } => Material::BlinnPhong { 
-				ambient: ambient.into(),
-				diffuse: diffuse.into(),
-				specular: specular.into(),
+impl MaterialFormat {
+	/// Names of the named materials this material refers to.
+	pub(crate) fn references(&self) -> Vec<&str> {
+		match self {
+			MaterialFormat::Named(name) => vec![name.as_str()],
+			MaterialFormat::Material(TaggedMaterialFormat::NormalMap { material, .. } | TaggedMaterialFormat::Bump { material, .. } | TaggedMaterialFormat::Cutout { material, .. }) => material.references(),
+			MaterialFormat::Material(TaggedMaterialFormat::Mix { a, b, .. } | TaggedMaterialFormat::Layered { base: a, coat: b, .. }) => [a.references(), b.references()].concat(),
+			MaterialFormat::Material(_) => Vec::new(),
+		}
+	}
+
+	/// Builds the material, looking up named materials and textures and loading
+	/// images through `context`.
+	pub fn load(self, context: &LoadContext) -> Result<Material, SceneError> {
+		let v = match self {
+			MaterialFormat::Named(name) => {
+				let material = context.materials.get(&name).ok_or_else(|| SceneError::UnknownMaterial { name: name.clone() })?;
+				return Ok(Material::Named { name, material: material.clone() });
+			}
+			MaterialFormat::Material(v) => v,
+		};
+		Ok(match v {
+			TaggedMaterialFormat::DebugPosition => Material::DebugPosition,
+			TaggedMaterialFormat::DebugNormals => Material::DebugNormals,
+			TaggedMaterialFormat::DebugShadows => Material::DebugShadows,
+			TaggedMaterialFormat::Emissive { color } => Material::Emissive { color: color.into() },
+			TaggedMaterialFormat::Mirror => Material::Mirror,
+			TaggedMaterialFormat::Dielectric { ior, tint } => Material::Dielectric {
+				ior,
+				tint: tint.map_or(Vector3::new(1.0, 1.0, 1.0), |tint| tint.into()),
+			},
+			TaggedMaterialFormat::BlinnPhong { ambient, diffuse, specular, intensity } => Material::BlinnPhong { 
+				ambient: ambient.load(context)?,
+				diffuse: diffuse.load(context)?,
+				specular: specular.load(context)?,
 				intensity
 			},
-		}
-    }
+			TaggedMaterialFormat::Glossy { tint, roughness, metal } => Material::Glossy {
+				tint: tint.load(context)?,
+				roughness,
+				metal: metal.map(|metal| metal.into()),
+			},
+			TaggedMaterialFormat::Pbr { base_color, metallic, roughness, ior } => Material::Pbr {
+				base_color: base_color.load(context)?,
+				metallic: metallic.load(context)?,
+				roughness: roughness.load(context)?,
+				ior: ior.load(context)?,
+			},
+			TaggedMaterialFormat::NormalMap { material, map, strength } => Material::NormalMap {
+				material: Arc::new(material.load(context)?),
+				map: map.load_map(context)?,
+				strength,
+			},
+			TaggedMaterialFormat::Bump { material, map, depth } => Material::Bump {
+				material: Arc::new(material.load(context)?),
+				map: map.load_map(context)?,
+				depth,
+			},
+			TaggedMaterialFormat::Cutout { material, opacity } => Material::Cutout {
+				material: Arc::new(material.load(context)?),
+				opacity: opacity.load(context)?,
+			},
+			TaggedMaterialFormat::Mix { a, b, factor } => Material::Mix {
+				a: load_blended(*a, context)?,
+				b: load_blended(*b, context)?,
+				factor: factor.load(context)?,
+			},
+			TaggedMaterialFormat::Layered { base, coat, coat_weight } => Material::Layered {
+				base: load_blended(*base, context)?,
+				coat: load_blended(*coat, context)?,
+				coat_weight: coat_weight.load(context)?,
+			},
+		})
+	}
+}
+
+impl TryFrom<MaterialFormat> for Material {
+	type Error = SceneError;
+
+	fn try_from(v: MaterialFormat) -> Result<Material, SceneError> {
+		v.load(&LoadContext::new(Path::new("")))
+	}
 }
 
 impl From<Material> for MaterialFormat {
     fn from(v: Material) -> MaterialFormat { 
-        match v {
-			Material::DebugPosition => MaterialFormat::DebugPosition,
-			Material::DebugNormals => MaterialFormat::DebugNormals,
-			Material::DebugShadows => MaterialFormat::DebugShadows,
-			Material::Emissive { color } => MaterialFormat::Emissive { color: color.into() },
-			Material::Mirror => MaterialFormat::Mirror,
-			Material::BlinnPhong { ambient, diffuse, specular, intensity } => MaterialFormat::BlinnPhong { 
+        MaterialFormat::Material(match v {
+			Material::Named { name, .. } => return MaterialFormat::Named(name),
+			Material::DebugPosition => TaggedMaterialFormat::DebugPosition,
+			Material::DebugNormals => TaggedMaterialFormat::DebugNormals,
+			Material::DebugShadows => TaggedMaterialFormat::DebugShadows,
+			Material::Emissive { color } => TaggedMaterialFormat::Emissive { color: color.into() },
+			Material::Mirror => TaggedMaterialFormat::Mirror,
+			Material::Dielectric { ior, tint } => TaggedMaterialFormat::Dielectric { ior, tint: Some(tint.into()) },
+			Material::BlinnPhong { ambient, diffuse, specular, intensity } => TaggedMaterialFormat::BlinnPhong { 
 				ambient: ambient.into(),
 				diffuse: diffuse.into(),
 				specular: specular.into(),
 				intensity
 			},
-		}
+			Material::Glossy { tint, roughness, metal } => TaggedMaterialFormat::Glossy {
+				tint: tint.into(),
+				roughness,
+				metal: metal.map(|metal| metal.into()),
+			},
+			Material::Pbr { base_color, metallic, roughness, ior } => TaggedMaterialFormat::Pbr {
+				base_color: base_color.into(),
+				metallic: metallic.into(),
+				roughness: roughness.into(),
+				ior: ior.into(),
+			},
+			Material::NormalMap { material, map, strength } => TaggedMaterialFormat::NormalMap {
+				material: Box::new(Arc::unwrap_or_clone(material).into()),
+				map: map.into(),
+				strength,
+			},
+			Material::Bump { material, map, depth } => TaggedMaterialFormat::Bump {
+				material: Box::new(Arc::unwrap_or_clone(material).into()),
+				map: map.into(),
+				depth,
+			},
+			Material::Cutout { material, opacity } => TaggedMaterialFormat::Cutout {
+				material: Box::new(Arc::unwrap_or_clone(material).into()),
+				opacity: opacity.into(),
+			},
+			Material::Mix { a, b, factor } => TaggedMaterialFormat::Mix {
+				a: Box::new(Arc::unwrap_or_clone(a).into()),
+				b: Box::new(Arc::unwrap_or_clone(b).into()),
+				factor: factor.into(),
+			},
+			Material::Layered { base, coat, coat_weight } => TaggedMaterialFormat::Layered {
+				base: Box::new(Arc::unwrap_or_clone(base).into()),
+				coat: Box::new(Arc::unwrap_or_clone(coat).into()),
+				coat_weight: coat_weight.into(),
+			},
+		})
     }
-}
\ No newline at end of file
+}
+
+/// A metal's complex index of refraction `eta + i k` at red, green and blue
+/// wavelengths, which sets its color and how that turns white toward grazing
+/// angles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metal {
+	Gold,
+	Silver,
+	Copper,
+	Aluminum,
+	Chromium,
+	Iron,
+	Custom {
+		eta: Vector3<f64>,
+		k: Vector3<f64>,
+	},
+}
+
+impl Metal {
+	/// Measured `eta` and `k` at about 650, 550 and 450 nm.
+	pub fn eta_k(&self) -> (Vector3<f64>, Vector3<f64>) {
+		match *self {
+			Metal::Gold => (Vector3::new(0.143, 0.374, 1.442), Vector3::new(3.983, 2.385, 1.603)),
+			Metal::Silver => (Vector3::new(0.155, 0.117, 0.138), Vector3::new(4.828, 3.122, 2.147)),
+			Metal::Copper => (Vector3::new(0.200, 0.924, 1.102), Vector3::new(3.912, 2.452, 2.142)),
+			Metal::Aluminum => (Vector3::new(1.657, 0.880, 0.521), Vector3::new(9.224, 6.270, 4.837)),
+			Metal::Chromium => (Vector3::new(3.107, 3.181, 2.323), Vector3::new(3.331, 3.329, 3.135)),
+			Metal::Iron => (Vector3::new(2.911, 2.950, 2.585), Vector3::new(3.089, 2.932, 2.767)),
+			Metal::Custom { eta, k } => (eta, k),
+		}
+	}
+
+	/// Fraction of light reflected per channel at an angle with cosine `cos_theta`.
+	pub fn fresnel(&self, cos_theta: f64) -> Vector3<f64> {
+		let (eta, k) = self.eta_k();
+		Vector3::new(
+			fresnel_conductor(cos_theta, eta.x, k.x),
+			fresnel_conductor(cos_theta, eta.y, k.y),
+			fresnel_conductor(cos_theta, eta.z, k.z),
+		)
+	}
+}
+
+/// One of the named metals, or `{"Custom": {"eta": ..., "k": ...}}`.
+#[derive(Serialize, Deserialize)]
+pub enum MetalFormat {
+	Gold,
+	Silver,
+	Copper,
+	Aluminum,
+	Chromium,
+	Iron,
+	Custom {
+		eta: RgbFormat,
+		k: RgbFormat,
+	},
+}
+
+impl From<MetalFormat> for Metal {
+	fn from(v: MetalFormat) -> Metal {
+		match v {
+			MetalFormat::Gold => Metal::Gold,
+			MetalFormat::Silver => Metal::Silver,
+			MetalFormat::Copper => Metal::Copper,
+			MetalFormat::Aluminum => Metal::Aluminum,
+			MetalFormat::Chromium => Metal::Chromium,
+			MetalFormat::Iron => Metal::Iron,
+			MetalFormat::Custom { eta, k } => Metal::Custom { eta: eta.into(), k: k.into() },
+		}
+	}
+}
+
+impl From<Metal> for MetalFormat {
+	fn from(v: Metal) -> MetalFormat {
+		match v {
+			Metal::Gold => MetalFormat::Gold,
+			Metal::Silver => MetalFormat::Silver,
+			Metal::Copper => MetalFormat::Copper,
+			Metal::Aluminum => MetalFormat::Aluminum,
+			Metal::Chromium => MetalFormat::Chromium,
+			Metal::Iron => MetalFormat::Iron,
+			Metal::Custom { eta, k } => MetalFormat::Custom { eta: eta.into(), k: k.into() },
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+	use crate::SceneError;
+	use crate::testing::{load_scene, sphere, expect_error};
+	use super::*;
+
+	#[test]
+	fn parses_factors() {
+		let parse = |json: &str| serde_json::from_str::<FactorFormat>(json).map_err(|error| error.to_string());
+		assert!(matches!(parse("0.25"), Ok(FactorFormat::Texture(TextureFormat::Scalar(v))) if v == 0.25));
+		assert!(matches!(parse(r#"{ "type": "Fresnel" }"#), Ok(FactorFormat::Fresnel(FresnelFormat::Fresnel { ior })) if ior == 1.5));
+		assert!(matches!(parse(r#"{ "type": "Fresnel", "ior": 2 }"#), Ok(FactorFormat::Fresnel(FresnelFormat::Fresnel { ior })) if ior == 2.0));
+		assert!(matches!(parse(r#"{ "type": "Checker", "a": 0, "b": 1 }"#), Ok(FactorFormat::Texture(TextureFormat::Texture(_)))));
+		assert!(parse(r#"{ "type": "Fresnel", "ior": "glass" }"#).is_err_and(|error| error.starts_with("invalid type: string \"glass\", expected f64")));
+	}
+
+	#[test]
+	fn no_lights() {
+		let error = expect_error(load_scene(json!({ "objects": [sphere(json!("DebugShadows"))] })),
+			"the DebugShadows material needs at least one light in the scene");
+		assert_eq!(error, SceneError::NoLights);
+
+		// Also when nested inside other materials.
+		let nested = json!({ "Mix": { "a": "Mirror", "b": { "Bump": { "material": "shadows", "map": 0, "depth": 1 } }, "factor": 0.5 } });
+		let error = load_scene(json!({ "materials": { "shadows": "DebugShadows" }, "objects": [sphere(nested)] })).err().unwrap();
+		assert_eq!(error, SceneError::NoLights);
+	}
+
+	#[test]
+	fn material_library() {
+		// Named materials can refer to each other in any order.
+		let materials = json!({
+			"a": { "Mix": { "a": "b", "b": "c", "factor": 0.5 } },
+			"b": { "Layered": { "base": "c", "coat": "Mirror", "coat_weight": 0.1 } },
+			"c": { "Dielectric": { "ior": 1.5 } },
+		});
+		let scene = load_scene(json!({ "materials": materials, "objects": [sphere(json!("a"))] })).unwrap();
+		assert_eq!(scene.materials.len(), 3);
+
+		let error = expect_error(load_scene(json!({ "materials": { "Mirror": { "Dielectric": { "ior": 1.5 } } } })),
+			"materials[\"Mirror\"]: the name is taken by the built-in Mirror material");
+		assert_eq!(error, SceneError::BuiltinMaterialName { name: String::from("Mirror") });
+
+		let materials = json!({
+			"a": { "Mix": { "a": "Mirror", "b": "b", "factor": 0.5 } },
+			"b": { "NormalMap": { "material": "c", "map": 0.5 } },
+			"c": { "Bump": { "material": "a", "map": 0, "depth": 1 } },
+		});
+		let error = expect_error(load_scene(json!({ "materials": materials })), "named materials refer to each other in a loop: a -> b -> c -> a");
+		assert!(matches!(error, SceneError::MaterialCycle { .. }));
+		let error = expect_error(load_scene(json!({ "materials": { "a": { "Mix": { "a": "a", "b": "Mirror", "factor": 0.5 } } } })),
+			"named materials refer to each other in a loop: a -> a");
+		assert!(matches!(error, SceneError::MaterialCycle { .. }));
+	}
+
+	#[test]
+	fn cutout_in_blend() {
+		let leaf = json!({ "Cutout": { "material": "Mirror", "opacity": 0.5 } });
+		let mix = json!({ "Mix": { "a": "Mirror", "b": { "Bump": { "material": leaf, "map": 0, "depth": 1 } }, "factor": 0.5 } });
+		let error = expect_error(load_scene(json!({ "objects": [sphere(mix)] })),
+			"objects[0]: Mix and Layered materials can't blend a Cutout, put the Cutout around the blend instead");
+		assert_eq!(error, SceneError::Object { index: 0, error: Box::new(SceneError::CutoutInBlend) });
+
+		// Also through a named material, while a Cutout around a blend is fine.
+		let layered = json!({ "Layered": { "base": "leaf", "coat": "Mirror", "coat_weight": 0.5 } });
+		let error = load_scene(json!({ "materials": { "leaf": leaf, "coated": layered } })).err().unwrap();
+		assert_eq!(error, SceneError::Material { name: String::from("coated"), error: Box::new(SceneError::CutoutInBlend) });
+		let cutout = json!({ "Cutout": { "material": { "Mix": { "a": "Mirror", "b": "DebugNormals", "factor": 0.5 } }, "opacity": 0.5 } });
+		assert!(load_scene(json!({ "objects": [sphere(cutout)] })).is_ok());
+	}
+
+	#[test]
+	fn unknown_material() {
+		let error = expect_error(load_scene(json!({ "objects": [sphere(json!("plastic"))] })),
+			"objects[0]: unknown material \"plastic\", named materials must be listed in the scene's materials");
+		assert_eq!(error, SceneError::Object { index: 0, error: Box::new(SceneError::UnknownMaterial { name: String::from("plastic") }) });
+	}
+}
@@ -1,15 +1,24 @@
 use serde::{Serialize, Deserialize};
-use cgmath::{Point3, Vector3, Rad, Matrix4};
-use cgmath::{InnerSpace, Matrix, Transform, One, MetricSpace, EuclideanSpace};
-use crate::{Ray, Hit, Intersect, Material, VectorFormat, MaterialFormat};
+use cgmath::{Point3, Vector2, Vector3, Vector4, Rad};
+use cgmath::{InnerSpace, EuclideanSpace};
+use std::path::Path;
+use std::sync::Arc;
+use crate::{Ray, Hit, Intersect, Material, VectorFormat, MaterialFormat, Aabb, Bounded, Bvh, BvhStats, Transform, AnimatedTransform, Components, KeyframeFormat, SceneError, LoadContext, orthogonal_tangent};
 
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(from = "MeshFormat", into = "MeshFormat")]
+#[serde(try_from = "MeshFormat", into = "MeshFormat")]
 pub struct Mesh {
 	pub vertices: Vec<Vector3<f64>>,
 	pub normals: Vec<Vector3<f64>>,
-	pub transform: Matrix4<f64>,
-	pub material: Material,
+	/// Per vertex texture coordinates, empty if the OBJ file has none.
+	pub texcoords: Vec<Vector2<f64>>,
+	/// Per vertex tangents generated from the texcoords, with the handedness of
+	/// the bitangent in w. Empty without texcoords.
+	pub tangents: Vec<Vector4<f64>>,
+	pub transform: AnimatedTransform,
+	pub material: Arc<Material>,
+	/// Object space BVH over the triangles, built once when the mesh is created.
+	bvh: Bvh,
 }
 
 struct TriangleHit {
@@ -20,17 +29,50 @@ struct TriangleHit {
 
 const EPSILON: f64 = 0.000001;
 impl Mesh {
-	fn new(vertices: Vec<Vector3<f64>>, normals: Vec<Vector3<f64>>, origin: Point3<f64>, scale: Vector3<f64>, rot_axis: Vector3<f64>, rot_angle: Rad<f64>, material: Material) -> Mesh {
+	fn new(vertices: Vec<Vector3<f64>>, normals: Vec<Vector3<f64>>, texcoords: Vec<Vector2<f64>>, tangents: Vec<Vector4<f64>>, transform: AnimatedTransform, material: Arc<Material>) -> Mesh {
+		let triangle_bounds: Vec<Aabb> = vertices.chunks_exact(3).map(|x| {
+			Aabb::from_points(x.iter().map(|&v| Point3::from_vec(v)))
+		}).collect();
 		Mesh {
+			bvh: Bvh::new(&triangle_bounds),
 			vertices,
 			normals,
-			transform: Matrix4::one()
-				* Matrix4::from_translation(origin.to_vec())
-				* Matrix4::from_axis_angle(rot_axis, rot_angle)
-				* Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z),
+			texcoords,
+			tangents,
+			transform,
 			material,
 		}
 	}
+	pub fn bvh_stats(&self) -> BvhStats {
+		self.bvh.stats()
+	}
+
+	/// Texture coordinates and world space `uv_density` at barycentric `(u, v)`
+	/// on triangle `i`, placed in the world by `transform`. Without texcoords the
+	/// barycentric coordinates are used.
+	fn triangle_uv(&self, transform: &Transform, i: usize, u: f64, v: f64) -> (Vector2<f64>, f64) {
+		let x = &self.vertices[3 * i..3 * i + 3];
+		let world_area = transform.vector_to_world(x[1] - x[0]).cross(transform.vector_to_world(x[2] - x[0])).magnitude();
+		let density = |uv_area: f64| if world_area > 0.0 { (uv_area / world_area).sqrt() } else { 0.0 };
+		if self.texcoords.len() != self.vertices.len() {
+			return (Vector2::new(u, v), density(1.0));
+		}
+		let t = &self.texcoords[3 * i..3 * i + 3];
+		let (duv1, duv2) = (t[1] - t[0], t[2] - t[0]);
+		(t[0] * (1.0 - u - v) + t[1] * u + t[2] * v, density((duv1.x * duv2.y - duv2.x * duv1.y).abs()))
+	}
+
+	/// Object space tangent and handedness of the bitangent at barycentric
+	/// `(u, v)` on triangle `i`, along the first edge without texcoords.
+	fn triangle_tangent(&self, i: usize, u: f64, v: f64) -> (Vector3<f64>, f64) {
+		let i = 3 * i;
+		if self.tangents.len() != self.vertices.len() {
+			return (self.vertices[i + 1] - self.vertices[i], 1.0);
+		}
+		let tangent = self.tangents[i] * (1.0 - u - v) + self.tangents[i + 1] * u + self.tangents[i + 2] * v;
+		(tangent.truncate(), if tangent.w < 0.0 { -1.0 } else { 1.0 })
+	}
+
 	fn intersect_triangle(&self, ray_origin: Vector3<f64>, ray_direction: Vector3<f64>, vert0: Vector3<f64>, vert1: Vector3<f64>, vert2: Vector3<f64>) -> Option<TriangleHit> {
 
 		/* find vectors for two edges sharing vert0 */
@@ -76,54 +118,72 @@ impl Mesh {
 		
 		if t < 0.0 { return None; }
 
-		return Some(TriangleHit{
+		Some(TriangleHit{
 			u,
 			v,
 			t,
-		});
+		})
 	}
 }
 
 impl Intersect for Mesh {
 	fn intersect(&self, ray: &Ray) -> Option<Hit> {
-		let transform_inv = self.transform.inverse_transform().unwrap();
-		
-		let trans_ray_origin = transform_inv.transform_point(ray.origin);
-		let trans_ray_origin_vec = Vector3::new(trans_ray_origin.x, trans_ray_origin.y, trans_ray_origin.z); // bruh
-		let trans_ray_direction = transform_inv.transform_vector(ray.direction).normalize();
-		
-		let hit = self.vertices.chunks_exact(3).enumerate().filter_map(|(i, x)|{
-			if let Some(triangle_hit) = self.intersect_triangle(trans_ray_origin_vec, trans_ray_direction, x[0], x[1], x[2]) {
+		let transform = self.transform.at(ray.time);
+		let object_ray = transform.ray_to_object(ray);
+		let hit = self.bvh.intersect(&object_ray, |i| {
+			let x = &self.vertices[3 * i..3 * i + 3];
+			if let Some(triangle_hit) = self.intersect_triangle(object_ray.origin.to_vec(), object_ray.direction, x[0], x[1], x[2]) {
 				let w = 1.0 - triangle_hit.u - triangle_hit.v;
 				let distance = triangle_hit.t;
 				let position = Point3::new(0.0, 0.0, 0.0) + (triangle_hit.u * x[1]) + (triangle_hit.v * x[2]) + (w * x[0]);
 				let normal = if self.normals.len() == self.vertices.len() {
-					let i = (i * 3) as usize;
-					(triangle_hit.u * self.normals[i + 1]) + (triangle_hit.v * self.normals[i + 2]) + (w * self.normals[i + 0])
+					let i = i * 3;
+					(triangle_hit.u * self.normals[i + 1]) + (triangle_hit.v * self.normals[i + 2]) + (w * self.normals[i])
 				} else {
 					(x[1] - x[0]).cross(x[2] - x[0])
 					
 				};
+				let normal = normal.normalize();
+				let (uv, uv_density) = self.triangle_uv(&transform, i, triangle_hit.u, triangle_hit.v);
+				let (tangent, handedness) = self.triangle_tangent(i, triangle_hit.u, triangle_hit.v);
 				Some(Hit {
 					distance,
 					position,
-					normal: normal.normalize(),
+					normal,
+					uv,
+					tangent,
+					bitangent: normal.cross(tangent) * handedness,
+					uv_density,
 					material: self.material.clone(),
 				})
 			} else {
 				None
 			}
-		}).min()?;
+		})?;
 		
-		let mut hit = Hit {
-			distance: ray.origin.distance(self.transform.transform_point(hit.position)),
-			position: self.transform.transform_point(hit.position),
-			normal: transform_inv.transpose().transform_vector(hit.normal).normalize(),
-			material: self.material.clone(),
-		};
-		if cgmath::dot(ray.direction, hit.position - ray.origin) < 0.0 { hit.distance *= -1.0 };
-		if hit.distance < 0.0 { return None; }
-		Some(hit)
+		let normal = transform.normal_to_world(hit.normal);
+		let tangent = orthogonal_tangent(transform.vector_to_world(hit.tangent), normal);
+		let bitangent = normal.cross(tangent);
+		// Mirroring transforms flip the handedness.
+		let bitangent = if cgmath::dot(bitangent, transform.vector_to_world(hit.bitangent)) < 0.0 { -bitangent } else { bitangent };
+		Some(Hit {
+			distance: hit.distance,
+			position: transform.point_to_world(hit.position),
+			normal,
+			uv: hit.uv,
+			tangent,
+			bitangent,
+			uv_density: hit.uv_density,
+			material: hit.material,
+		})
+	}
+}
+
+impl Bounded for Mesh {
+	fn bounds(&self) -> Option<Aabb> {
+		let object_bounds = Aabb::from_points(self.vertices.iter().map(|&v| Point3::from_vec(v)));
+		if object_bounds.is_empty() { return None; }
+		Some(self.transform.bounds(|transform| object_bounds.transform(transform.matrix())))
 	}
 }
 
@@ -135,21 +195,82 @@ pub struct MeshFormat {
 	pub rot_axis: VectorFormat,
 	pub rot_angle: f64,
 	pub material: MaterialFormat,
+	/// Placement at other times, for a moving mesh.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub motion: Vec<KeyframeFormat>,
+}
+
+/// Tangents of every vertex of `mesh`, averaged over the triangles sharing it,
+/// with the handedness of the bitangent in w. Empty if `mesh` has no texcoords.
+fn vertex_tangents(mesh: &tobj::Mesh) -> Vec<Vector4<f64>> {
+	if mesh.texcoords.is_empty() {
+		return vec![];
+	}
+	let vertex_count = mesh.positions.len() / 3;
+	let position = |i: usize| vector3(&mesh.positions, i);
+	let texcoord = |i: usize| Vector2::new(mesh.texcoords[2 * i], mesh.texcoords[2 * i + 1]);
+	let zero = Vector3::new(0.0, 0.0, 0.0);
+	let (mut tangents, mut bitangents, mut normals) = (vec![zero; vertex_count], vec![zero; vertex_count], vec![zero; vertex_count]);
+
+	for triangle in mesh.indices.chunks_exact(3) {
+		let [a, b, c] = [triangle[0] as usize, triangle[1] as usize, triangle[2] as usize];
+		let (edge1, edge2) = (position(b) - position(a), position(c) - position(a));
+		let (duv1, duv2) = (texcoord(b) - texcoord(a), texcoord(c) - texcoord(a));
+		let determinant = duv1.x * duv2.y - duv2.x * duv1.y;
+		let normal = edge1.cross(edge2);
+		for i in [a, b, c] {
+			normals[i] += normal;
+		}
+		if determinant.abs() < EPSILON { continue; }
+		let tangent = (edge1 * duv2.y - edge2 * duv1.y) / determinant;
+		let bitangent = (edge2 * duv1.x - edge1 * duv2.x) / determinant;
+		for i in [a, b, c] {
+			tangents[i] += tangent;
+			bitangents[i] += bitangent;
+		}
+	}
+
+	(0..vertex_count).map(|i| {
+		let normal = if mesh.normals.is_empty() { normals[i] } else { vector3(&mesh.normals, i) };
+		if normal.magnitude2() == 0.0 {
+			return Vector4::new(1.0, 0.0, 0.0, 1.0);
+		}
+		let normal = normal.normalize();
+		let tangent = orthogonal_tangent(tangents[i], normal);
+		let handedness = if cgmath::dot(normal.cross(tangent), bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+		tangent.extend(handedness)
+	}).collect()
 }
 
-impl From<MeshFormat> for Mesh {
-    fn from(v: MeshFormat) -> Mesh {
+/// The `i`th vector of a flat list of coordinates.
+fn vector3(values: &[f64], i: usize) -> Vector3<f64> {
+	Vector3::new(values[3 * i], values[3 * i + 1], values[3 * i + 2])
+}
+
+impl Mesh {
+	/// Loads the OBJ file named by `v.filename` through `context`, like images.
+	pub fn load(v: MeshFormat, context: &LoadContext) -> Result<Mesh, SceneError> {
 		let options = tobj::LoadOptions {
 			single_index: true,
 			triangulate: false,
 			ignore_points: true,
 			ignore_lines: true,
 		};
-		let model_file = tobj::load_obj(v.filename, &options);
-		let (mut models, _) = model_file.expect("Failed to load OBJ file!");
+		let path = context.path(&v.filename);
+		let map_error = |message: String| SceneError::ObjLoad { path: path.clone(), message };
+		let bytes = context.read(&v.filename).map_err(|error| map_error(error.to_string()))?;
+		// Materials come from the scene, so the OBJ's material libraries aren't read.
+		let (mut models, _) = tobj::load_obj_buf(&mut &bytes[..], &options, |_| Err(tobj::LoadError::OpenFileFailed))
+			.map_err(|error| map_error(error.to_string()))?;
+		if models.is_empty() {
+			return Err(SceneError::ObjLoad { path, message: String::from("file contains no models") });
+		}
 		let mesh = models.remove(0).mesh;
+		let vertex_tangents = vertex_tangents(&mesh);
 		let mut vertices: Vec<Vector3<f64>> = vec![];
 		let mut normals: Vec<Vector3<f64>> = vec![];
+		let mut texcoords: Vec<Vector2<f64>> = vec![];
+		let mut tangents: Vec<Vector4<f64>> = vec![];
 		
 		for index in &mesh.indices {
 			let pos_offset = (3 * index) as usize;
@@ -169,21 +290,99 @@ impl From<MeshFormat> for Mesh {
 				);
 				normals.push(normal);
 			}
+			
+			if !mesh.texcoords.is_empty() {
+				let texcoord_offset = (2 * index) as usize;
+				texcoords.push(Vector2::new(mesh.texcoords[texcoord_offset], mesh.texcoords[texcoord_offset + 1]));
+				tangents.push(vertex_tangents[*index as usize]);
+			}
 		}
 		
-		Mesh::new(vertices, normals, v.origin.into(), v.scale.into(), v.rot_axis.into(), Rad(v.rot_angle), v.material.into())
+		let components = Components {
+			origin: v.origin.into(),
+			scale: v.scale.into(),
+			rot_axis: v.rot_axis.into(),
+			rot_angle: Rad(v.rot_angle),
+		};
+		let transform = AnimatedTransform::load(components, v.motion)?;
+		Ok(Mesh::new(vertices, normals, texcoords, tangents, transform, Arc::new(v.material.load(context)?)))
+	}
+}
+
+impl TryFrom<MeshFormat> for Mesh {
+	type Error = SceneError;
+
+    fn try_from(v: MeshFormat) -> Result<Mesh, SceneError> {
+		Mesh::load(v, &LoadContext::new(Path::new("")))
     }
 }
 
 impl From<Mesh> for MeshFormat {
     fn from(v: Mesh) -> MeshFormat {
+		let (components, motion) = v.transform.to_format();
         MeshFormat {
 			filename: String::from("[filename]"),
-            origin: Point3::new(0.0, 0.0, 0.0).into(),
-			scale: Vector3::new(0.0, 0.0, 0.0).into(),
-			rot_axis: Vector3::new(0.0, 0.0, 0.0).into(),
-			rot_angle: 0.0,
-            material: v.material.into(),
+            origin: components.origin.into(),
+			scale: components.scale.into(),
+			rot_axis: components.rot_axis.into(),
+			rot_angle: components.rot_angle.0,
+            material: Arc::unwrap_or_clone(v.material).into(),
+			motion,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::{SeedableRng, rngs::StdRng};
+	use crate::Keyframes;
+	use crate::testing::{random_vector, load_scene};
+
+	#[test]
+	fn bvh_matches_brute_force() {
+		let mut rng = StdRng::seed_from_u64(2);
+		let vertices: Vec<Vector3<f64>> = (0..500).flat_map(|_| {
+			let center = random_vector(&mut rng, 5.0);
+			[center + random_vector(&mut rng, 0.8), center + random_vector(&mut rng, 0.8), center + random_vector(&mut rng, 0.8)]
+		}).collect();
+		let components = Components {
+			origin: Point3::new(0.0, 0.0, 0.0),
+			scale: Vector3::new(1.0, 1.0, 1.0),
+			rot_axis: Vector3::new(0.0, 1.0, 0.0),
+			rot_angle: Rad(0.0),
+		};
+		let transform = AnimatedTransform::new(Keyframes::constant(components)).unwrap();
+		let mesh = Mesh::new(vertices.clone(), vec![], vec![], vec![], transform, Arc::new(Material::DebugNormals));
+
+		let mut hits = 0;
+		for _ in 0..2000 {
+			let origin = random_vector(&mut rng, 8.0);
+			let direction = (random_vector(&mut rng, 4.0) - origin).normalize();
+			let expected = vertices.chunks_exact(3)
+				.filter_map(|x| mesh.intersect_triangle(origin, direction, x[0], x[1], x[2]))
+				.map(|hit| hit.t)
+				.min_by(f64::total_cmp);
+			let ray = Ray { origin: Point3::from_vec(origin), direction, spread: 0.0, time: 0.0 };
+			let actual = mesh.intersect(&ray).map(|hit| hit.distance);
+			match (expected, actual) {
+				(Some(expected), Some(actual)) => assert!((expected - actual).abs() < 1e-9, "expected {} got {}", expected, actual),
+				(expected, actual) => assert_eq!(expected, actual),
+			}
+			hits += expected.is_some() as usize;
+		}
+		assert!(hits > 500, "only {} rays hit", hits);
+	}
+
+	#[test]
+	fn missing_obj_file() {
+		let mesh = serde_json::json!({
+			"type": "Mesh", "filename": "missing.obj",
+			"origin": { "x": 0, "y": 0, "z": 0 }, "scale": { "x": 1, "y": 1, "z": 1 }, "rot_axis": { "x": 0, "y": 1, "z": 0 }, "rot_angle": 0,
+			"material": "Mirror",
+		});
+		let error = load_scene(serde_json::json!({ "objects": [mesh] })).err().unwrap();
+		assert!(matches!(error, SceneError::Object { index: 0, error: ref inner } if matches!(**inner, SceneError::ObjLoad { .. })));
+		assert!(error.to_string().starts_with("objects[0]: failed to load OBJ file missing.obj: "));
+	}
+}
@@ -0,0 +1,169 @@
+use std::f64::consts::PI;
+use cgmath::Vector3;
+use cgmath::{InnerSpace, ElementWise};
+use rand::Rng;
+use crate::{Hit, Texture, Metal, reflect};
+use crate::sampling::{cosine_hemisphere, ggx_visible_normal, luminance};
+
+/// Smallest GGX alpha used, perfectly smooth surfaces would need a delta lobe.
+const MIN_ALPHA: f64 = 1e-3;
+
+/// GGX normal distribution.
+fn distribution(alpha: f64, n_dot_h: f64) -> f64 {
+	let alpha2 = alpha * alpha;
+	let d = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+	alpha2 / (PI * d * d)
+}
+
+/// Smith's lambda for GGX, the ratio of hidden to visible microfacet area.
+fn lambda(alpha: f64, cos_theta: f64) -> f64 {
+	let cos2 = cos_theta * cos_theta;
+	let tan2 = (1.0 - cos2).max(0.0) / cos2;
+	((1.0 + alpha * alpha * tan2).sqrt() - 1.0) / 2.0
+}
+
+/// GGX specular BRDF without the Fresnel term, with height correlated Smith
+/// masking-shadowing. Zero unless both directions are above the surface.
+fn specular(alpha: f64, n: Vector3<f64>, wo: Vector3<f64>, wi: Vector3<f64>) -> f64 {
+	let (n_dot_v, n_dot_l) = (cgmath::dot(n, wo), cgmath::dot(n, wi));
+	if n_dot_v <= 0.0 || n_dot_l <= 0.0 { return 0.0; }
+	let h = (wo + wi).normalize();
+	let g = 1.0 / (1.0 + lambda(alpha, n_dot_v) + lambda(alpha, n_dot_l));
+	distribution(alpha, cgmath::dot(n, h)) * g / (4.0 * n_dot_v * n_dot_l)
+}
+
+/// Density of reflecting `wo` about a visible GGX normal to get `wi`, which is
+/// the visible normal density divided by the Jacobian of the reflection.
+fn specular_pdf(alpha: f64, n: Vector3<f64>, wo: Vector3<f64>, wi: Vector3<f64>) -> f64 {
+	let (n_dot_v, n_dot_l) = (cgmath::dot(n, wo), cgmath::dot(n, wi));
+	if n_dot_v <= 0.0 || n_dot_l <= 0.0 { return 0.0; }
+	let h = (wo + wi).normalize();
+	distribution(alpha, cgmath::dot(n, h)) / (4.0 * n_dot_v * (1.0 + lambda(alpha, n_dot_v)))
+}
+
+/// The `Pbr` material at one point: a GGX specular layer over a Lambertian
+/// base for dielectrics, or only the specular layer tinted by `base_color` for
+/// metals, blended by `metallic` as in glTF. All directions are unit vectors
+/// pointing away from the surface, with `n` on the side of `wo`.
+pub(crate) struct Microfacet {
+	base_color: Vector3<f64>,
+	metallic: f64,
+	alpha: f64,
+	/// Reflectance of the dielectric at normal incidence.
+	f0: f64,
+}
+
+/// Schlick's weight for the Fresnel term, `(1 - cos)^5`.
+fn schlick_weight(cos_theta: f64) -> f64 {
+	(1.0 - cos_theta).clamp(0.0, 1.0).powi(5)
+}
+
+impl Microfacet {
+	/// Looks the parameters up at `hit`. Scalar parameters read the red channel.
+	pub(crate) fn evaluate(base_color: &Texture, metallic: &Texture, roughness: &Texture, ior: &Texture, hit: &Hit, footprint: f64) -> Microfacet {
+		let lookup = |texture: &Texture| texture.evaluate(hit.uv, hit.position, footprint);
+		let roughness = lookup(roughness).x.clamp(0.0, 1.0);
+		let ior = lookup(ior).x;
+		Microfacet {
+			base_color: lookup(base_color),
+			metallic: lookup(metallic).x.clamp(0.0, 1.0),
+			alpha: (roughness * roughness).max(MIN_ALPHA),
+			f0: ((ior - 1.0) / (ior + 1.0)).powi(2),
+		}
+	}
+
+	/// Chance of sampling the specular lobe rather than the diffuse one when
+	/// seen from `wo`, in proportion to how much each reflects.
+	fn specular_probability(&self, n: Vector3<f64>, wo: Vector3<f64>) -> f64 {
+		let w = schlick_weight(cgmath::dot(n, wo));
+		let dielectric = self.f0 + (1.0 - self.f0) * w;
+		let metal = luminance(self.base_color + (Vector3::new(1.0, 1.0, 1.0) - self.base_color) * w);
+		let specular = dielectric * (1.0 - self.metallic) + metal * self.metallic;
+		let diffuse = luminance(self.base_color) * (1.0 - dielectric) * (1.0 - self.metallic);
+		if specular + diffuse > 0.0 { specular / (specular + diffuse) } else { 1.0 }
+	}
+
+	/// BRDF for light arriving from `wi` and leaving toward `wo`.
+	pub(crate) fn eval(&self, n: Vector3<f64>, wo: Vector3<f64>, wi: Vector3<f64>) -> Vector3<f64> {
+		let (n_dot_v, n_dot_l) = (cgmath::dot(n, wo), cgmath::dot(n, wi));
+		if n_dot_v <= 0.0 || n_dot_l <= 0.0 { return Vector3::new(0.0, 0.0, 0.0); }
+		let w = schlick_weight(cgmath::dot(wo, (wo + wi).normalize()));
+		let specular = specular(self.alpha, n, wo, wi);
+
+		let fresnel = self.f0 + (1.0 - self.f0) * w;
+		// The base only gets the light the specular layer doesn't reflect, which
+		// for a smooth layer seen along `wo` is about the Fresnel term at `n.wo`.
+		let transmitted = 1.0 - (self.f0 + (1.0 - self.f0) * schlick_weight(n_dot_v));
+		let dielectric = self.base_color * (transmitted / PI) + Vector3::new(1.0, 1.0, 1.0) * (specular * fresnel);
+		let metal = (self.base_color + (Vector3::new(1.0, 1.0, 1.0) - self.base_color) * w) * specular;
+		dielectric * (1.0 - self.metallic) + metal * self.metallic
+	}
+
+	/// Density `sample` picks `wi` with.
+	pub(crate) fn pdf(&self, n: Vector3<f64>, wo: Vector3<f64>, wi: Vector3<f64>) -> f64 {
+		let n_dot_l = cgmath::dot(n, wi);
+		if cgmath::dot(n, wo) <= 0.0 || n_dot_l <= 0.0 { return 0.0; }
+		let p = self.specular_probability(n, wo);
+		p * specular_pdf(self.alpha, n, wo, wi) + (1.0 - p) * n_dot_l / PI
+	}
+
+	/// Direction for light to arrive from, reflected about a visible GGX normal
+	/// or cosine distributed. It may point below the surface, where `pdf` is zero.
+	pub(crate) fn sample<R: Rng>(&self, n: Vector3<f64>, wo: Vector3<f64>, rng: &mut R) -> Vector3<f64> {
+		if rng.gen::<f64>() < self.specular_probability(n, wo) {
+			let h = ggx_visible_normal(n, wo, self.alpha, rng);
+			reflect(-wo, h)
+		} else {
+			cosine_hemisphere(n, rng)
+		}
+	}
+}
+
+/// The `Glossy` material at one point: a GGX reflection with no base under it,
+/// whose color comes from `tint` and, for metals, their Fresnel reflectance.
+pub(crate) struct Glossy {
+	tint: Vector3<f64>,
+	alpha: f64,
+	metal: Option<Metal>,
+}
+
+impl Glossy {
+	pub(crate) fn evaluate(tint: &Texture, roughness: f64, metal: Option<Metal>, hit: &Hit, footprint: f64) -> Glossy {
+		let roughness = roughness.clamp(0.0, 1.0);
+		Glossy {
+			tint: tint.evaluate(hit.uv, hit.position, footprint),
+			alpha: roughness * roughness,
+			metal,
+		}
+	}
+
+	/// Whether the surface is smooth enough to reflect like a mirror, in which
+	/// case only `fresnel` and `reflect` are meaningful.
+	pub(crate) fn is_smooth(&self) -> bool {
+		self.alpha < MIN_ALPHA
+	}
+
+	/// Fraction of light a facet reflects at an angle with cosine `cos_theta`.
+	pub(crate) fn fresnel(&self, cos_theta: f64) -> Vector3<f64> {
+		match self.metal {
+			Some(metal) => self.tint.mul_element_wise(metal.fresnel(cos_theta)),
+			None => self.tint,
+		}
+	}
+
+	pub(crate) fn eval(&self, n: Vector3<f64>, wo: Vector3<f64>, wi: Vector3<f64>) -> Vector3<f64> {
+		let specular = specular(self.alpha, n, wo, wi);
+		if specular <= 0.0 { return Vector3::new(0.0, 0.0, 0.0); }
+		self.fresnel(cgmath::dot(wo, (wo + wi).normalize())) * specular
+	}
+
+	pub(crate) fn pdf(&self, n: Vector3<f64>, wo: Vector3<f64>, wi: Vector3<f64>) -> f64 {
+		specular_pdf(self.alpha, n, wo, wi)
+	}
+
+	/// Reflection of `wo` about a visible GGX normal. It may point below the
+	/// surface, where `pdf` is zero.
+	pub(crate) fn sample<R: Rng>(&self, n: Vector3<f64>, wo: Vector3<f64>, rng: &mut R) -> Vector3<f64> {
+		reflect(-wo, ggx_visible_normal(n, wo, self.alpha, rng))
+	}
+}
@@ -0,0 +1,224 @@
+use std::f64::consts::PI;
+use cgmath::{Point3, Vector3};
+use cgmath::{InnerSpace, ElementWise};
+use rand::Rng;
+use crate::microfacet::{Microfacet, Glossy};
+use crate::{Ray, Intersect, Scene, Material, shade_ray, reflect, refract, fresnel_dielectric, texture_footprint, pick_material};
+use crate::sampling::{cosine_hemisphere, power_cosine, luminance};
+
+/// Bounce after which paths are randomly terminated based on their throughput.
+const RUSSIAN_ROULETTE_DEPTH: u32 = 3;
+
+fn offset_ray(origin: Point3<f64>, direction: Vector3<f64>, spread: f64, time: f64) -> Ray {
+	let mut ray = Ray {
+		origin,
+		direction: direction.normalize(),
+		spread,
+		time,
+	};
+	ray.origin = ray.at(0.0001);
+	ray
+}
+
+/// Normalized Blinn-Phong BRDF: a Lambertian `diffuse` lobe plus an energy
+/// preserving `specular` lobe with exponent `shininess`.
+fn blinn_phong_brdf(diffuse: Vector3<f64>, specular: Vector3<f64>, shininess: f64, n: Vector3<f64>, wo: Vector3<f64>, wi: Vector3<f64>) -> Vector3<f64> {
+	let h = (wo + wi).normalize();
+	let n_dot_h = cgmath::dot(n, h).max(0.0);
+	diffuse / PI + specular * ((shininess + 8.0) / (8.0 * PI) * n_dot_h.powf(shininess))
+}
+
+/// Direct lighting from one sample of every scene light. Light intensities are
+/// scaled by PI so that a white Lambertian surface receives the same light as it
+/// does under `shade_ray`. Shadow rays are traced at `time`.
+fn sample_lights<R: Rng>(scene: &Scene, position: Point3<f64>, time: f64, n: Vector3<f64>, brdf: impl Fn(Vector3<f64>) -> Vector3<f64>, rng: &mut R) -> Vector3<f64> {
+	let mut radiance = Vector3::new(0.0, 0.0, 0.0);
+	for light in &scene.lights {
+		let sample = light.sample(position, rng);
+		let wi = sample.direction;
+		let cos_theta = cgmath::dot(n, wi);
+		if cos_theta <= 0.0 { continue; }
+
+		let shadow_ray = offset_ray(position, wi, 0.0, time);
+		if let Some(shadow_hit) = scene.objects.intersect(&shadow_ray) {
+			if shadow_hit.distance <= sample.distance { continue; }
+		}
+		radiance += (sample.radiance * PI).mul_element_wise(brdf(wi)) * cos_theta;
+	}
+	radiance
+}
+
+/// Power heuristic weight for a sample with density `pdf` that could also have
+/// been drawn with density `other_pdf`.
+fn mis_weight(pdf: f64, other_pdf: f64) -> f64 {
+	pdf * pdf / (pdf * pdf + other_pdf * other_pdf)
+}
+
+/// Direct lighting from one importance sampled environment direction, weighted
+/// against the chance of the BRDF sampling the same direction. Black without
+/// an environment.
+fn sample_environment<R: Rng>(scene: &Scene, position: Point3<f64>, time: f64, n: Vector3<f64>, brdf: impl Fn(Vector3<f64>) -> Vector3<f64>, brdf_pdf: impl Fn(Vector3<f64>) -> f64, rng: &mut R) -> Vector3<f64> {
+	let Some(environment) = &scene.environment else { return Vector3::new(0.0, 0.0, 0.0); };
+	let (wi, pdf) = environment.sample(rng);
+	let cos_theta = cgmath::dot(n, wi);
+	if pdf <= 0.0 || cos_theta <= 0.0 { return Vector3::new(0.0, 0.0, 0.0); }
+	if scene.objects.intersect(&offset_ray(position, wi, 0.0, time)).is_some() { return Vector3::new(0.0, 0.0, 0.0); }
+	environment.radiance(wi).mul_element_wise(brdf(wi)) * (cos_theta * mis_weight(pdf, brdf_pdf(wi)) / pdf)
+}
+
+/// Estimates the radiance arriving along `ray` with a unidirectional path tracer.
+///
+/// Diffuse and glossy surfaces use next-event estimation toward the scene lights
+/// and continue the path by importance sampling their BRDF. `Emissive` objects
+/// contribute whenever a path hits them, so they act as real (if noisier) light
+/// sources. The environment is sampled the same way and combined with BRDF
+/// sampling by multiple importance sampling. Paths longer than
+/// `RUSSIAN_ROULETTE_DEPTH` are terminated randomly.
+pub fn trace_path<R: Rng>(scene: &Scene, ray: &Ray, max_bounces: u32, rng: &mut R) -> Vector3<f64> {
+	let Scene { ref objects, ref environment, .. } = *scene;
+	let mut radiance = Vector3::new(0.0, 0.0, 0.0);
+	let mut throughput = Vector3::new(1.0, 1.0, 1.0);
+	let mut ray = Ray {
+		origin: ray.origin,
+		direction: ray.direction.normalize(),
+		spread: ray.spread,
+		time: ray.time,
+	};
+	// Density the last diffuse or glossy bounce chose `ray` with, `None` after
+	// the camera and perfectly specular bounces.
+	let mut brdf_pdf: Option<f64> = None;
+
+	for depth in 0..=max_bounces {
+		let mut hit = match objects.intersect(&ray) {
+			Some(hit) => hit,
+			None => {
+				if let Some(environment) = environment {
+					let weight = brdf_pdf.map_or(1.0, |pdf| mis_weight(pdf, environment.pdf(ray.direction)));
+					radiance += throughput.mul_element_wise(environment.radiance(ray.direction)) * weight;
+				}
+				break;
+			}
+		};
+		throughput *= pick_material(&ray, &mut hit, rng);
+		let wo = -ray.direction;
+		let footprint = texture_footprint(&ray, &hit);
+
+		match *hit.material {
+			Material::DebugPosition | Material::DebugNormals | Material::DebugShadows => {
+				if let Some(color) = shade_ray(scene, &ray, 0, rng) {
+					radiance += throughput.mul_element_wise(color);
+				}
+				break;
+			}
+			Material::Emissive { color } => {
+				radiance += throughput.mul_element_wise(color);
+				break;
+			}
+			Material::Mirror => {
+				ray = offset_ray(hit.position, reflect(ray.direction, hit.normal), ray.spread, ray.time);
+				brdf_pdf = None;
+			}
+			Material::Dielectric { ior, tint } => {
+				let entering = cgmath::dot(ray.direction, hit.normal) < 0.0;
+				let (normal, eta) = if entering { (hit.normal, 1.0 / ior) } else { (-hit.normal, ior) };
+				let cos_i = cgmath::dot(wo, normal).min(1.0);
+				let reflectance = fresnel_dielectric(cos_i, eta);
+				let direction = match refract(ray.direction, normal, eta) {
+					Some(refracted) if rng.gen::<f64>() >= reflectance => {
+						throughput = throughput.mul_element_wise(tint);
+						refracted
+					}
+					_ => reflect(ray.direction, normal),
+				};
+				ray = offset_ray(hit.position, direction, ray.spread, ray.time);
+				brdf_pdf = None;
+			}
+			Material::BlinnPhong { ref diffuse, ref specular, intensity, .. } => {
+				let diffuse = diffuse.evaluate(hit.uv, hit.position, footprint);
+				let specular = specular.evaluate(hit.uv, hit.position, footprint);
+				let n = if cgmath::dot(hit.normal, wo) < 0.0 { -hit.normal } else { hit.normal };
+				let brdf = |wi| blinn_phong_brdf(diffuse, specular, intensity, n, wo, wi);
+
+				// Lobes are picked in proportion to their brightness.
+				let diffuse_weight = luminance(diffuse);
+				let specular_weight = luminance(specular);
+				if diffuse_weight + specular_weight <= 0.0 { break; }
+				let specular_probability = specular_weight / (diffuse_weight + specular_weight);
+				let pdf = |wi: Vector3<f64>| {
+					let cos_theta = cgmath::dot(n, wi);
+					if cos_theta <= 0.0 { return 0.0; }
+					let h = (wo + wi).normalize();
+					let n_dot_h = cgmath::dot(n, h).max(0.0);
+					let diffuse_pdf = cos_theta / PI;
+					let specular_pdf = (intensity + 1.0) / (2.0 * PI) * n_dot_h.powf(intensity) / (4.0 * cgmath::dot(wo, h).abs().max(1e-8));
+					(1.0 - specular_probability) * diffuse_pdf + specular_probability * specular_pdf
+				};
+
+				radiance += throughput.mul_element_wise(sample_lights(scene, hit.position, ray.time, n, brdf, rng));
+				radiance += throughput.mul_element_wise(sample_environment(scene, hit.position, ray.time, n, brdf, pdf, rng));
+
+				let wi = if rng.gen::<f64>() < specular_probability {
+					let h = power_cosine(n, intensity, rng);
+					reflect(-wo, h)
+				} else {
+					cosine_hemisphere(n, rng)
+				};
+				let cos_theta = cgmath::dot(n, wi);
+				if cos_theta <= 0.0 { break; }
+				let wi_pdf = pdf(wi);
+				if wi_pdf <= 0.0 { break; }
+				throughput = throughput.mul_element_wise(brdf(wi)) * (cos_theta / wi_pdf);
+				ray = offset_ray(hit.position, wi, ray.spread, ray.time);
+				brdf_pdf = Some(wi_pdf);
+			}
+			Material::Glossy { ref tint, roughness, metal } => {
+				let glossy = Glossy::evaluate(tint, roughness, metal, &hit, footprint);
+				let n = if cgmath::dot(hit.normal, wo) < 0.0 { -hit.normal } else { hit.normal };
+				if glossy.is_smooth() {
+					throughput = throughput.mul_element_wise(glossy.fresnel(cgmath::dot(wo, n)));
+					ray = offset_ray(hit.position, reflect(ray.direction, n), ray.spread, ray.time);
+					brdf_pdf = None;
+				} else {
+					let brdf = |wi| glossy.eval(n, wo, wi);
+					let pdf = |wi| glossy.pdf(n, wo, wi);
+
+					radiance += throughput.mul_element_wise(sample_lights(scene, hit.position, ray.time, n, brdf, rng));
+					radiance += throughput.mul_element_wise(sample_environment(scene, hit.position, ray.time, n, brdf, pdf, rng));
+
+					let wi = glossy.sample(n, wo, rng);
+					let wi_pdf = pdf(wi);
+					if wi_pdf <= 0.0 { break; }
+					throughput = throughput.mul_element_wise(brdf(wi)) * (cgmath::dot(n, wi) / wi_pdf);
+					ray = offset_ray(hit.position, wi, ray.spread, ray.time);
+					brdf_pdf = Some(wi_pdf);
+				}
+			}
+			Material::Pbr { ref base_color, ref metallic, ref roughness, ref ior } => {
+				let microfacet = Microfacet::evaluate(base_color, metallic, roughness, ior, &hit, footprint);
+				let n = if cgmath::dot(hit.normal, wo) < 0.0 { -hit.normal } else { hit.normal };
+				let brdf = |wi| microfacet.eval(n, wo, wi);
+				let pdf = |wi| microfacet.pdf(n, wo, wi);
+
+				radiance += throughput.mul_element_wise(sample_lights(scene, hit.position, ray.time, n, brdf, rng));
+				radiance += throughput.mul_element_wise(sample_environment(scene, hit.position, ray.time, n, brdf, pdf, rng));
+
+				let wi = microfacet.sample(n, wo, rng);
+				let cos_theta = cgmath::dot(n, wi);
+				if cos_theta <= 0.0 { break; }
+				let wi_pdf = pdf(wi);
+				if wi_pdf <= 0.0 { break; }
+				throughput = throughput.mul_element_wise(brdf(wi)) * (cos_theta / wi_pdf);
+				ray = offset_ray(hit.position, wi, ray.spread, ray.time);
+				brdf_pdf = Some(wi_pdf);
+			}
+			Material::Named { .. } | Material::Cutout { .. } | Material::NormalMap { .. } | Material::Bump { .. } | Material::Mix { .. } | Material::Layered { .. } => unreachable!("removed by pick_material"),
+		}
+
+		if depth >= RUSSIAN_ROULETTE_DEPTH {
+			let survival = throughput.x.max(throughput.y).max(throughput.z).min(0.95);
+			if rng.gen::<f64>() >= survival { break; }
+			throughput /= survival;
+		}
+	}
+	radiance
+}
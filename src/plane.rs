@@ -1,13 +1,29 @@
 use serde::{Serialize, Deserialize};
-use cgmath::{Point3, Vector3};
-use crate::{Ray, Hit, Intersect, Material, VectorFormat, MaterialFormat};
+use cgmath::{Point3, Vector2, Vector3};
+use cgmath::InnerSpace;
+use std::path::Path;
+use std::sync::Arc;
+use crate::{Ray, Hit, Intersect, Material, VectorFormat, MaterialFormat, Aabb, Bounded, LoadContext, SceneError};
 
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(from = "PlaneFormat", into = "PlaneFormat")]
+#[serde(try_from = "PlaneFormat", into = "PlaneFormat")]
 pub struct Plane {
 	pub origin: Point3<f64>,
 	pub normal: Vector3<f64>,
-	pub material: Material,
+	/// Size in world units of one repeat of a texture across the plane.
+	pub tile_size: f64,
+	pub material: Arc<Material>,
+}
+
+impl Plane {
+	/// Directions of increasing u and v on the plane. Texture v points up on
+	/// walls and away from the default camera on floors.
+	fn texture_axes(&self) -> (Vector3<f64>, Vector3<f64>) {
+		let normal = self.normal.normalize();
+		let up = if normal.y.abs() < 0.999 { Vector3::new(0.0, 1.0, 0.0) } else { Vector3::new(0.0, 0.0, -1.0) };
+		let tangent = up.cross(normal).normalize();
+		(tangent, normal.cross(tangent))
+	}
 }
 
 impl Intersect for Plane {
@@ -16,30 +32,57 @@ impl Intersect for Plane {
 		let position = ray.at(distance);
 		let normal = self.normal;
 		if distance < 0.0 { return None };
+		let (tangent, bitangent) = self.texture_axes();
+		let offset = position - self.origin;
 		Some(Hit {
 			distance,
 			position,
 			normal,
+			uv: Vector2::new(cgmath::dot(offset, tangent), cgmath::dot(offset, bitangent)) / self.tile_size,
+			tangent,
+			bitangent,
+			uv_density: 1.0 / self.tile_size,
 			material: self.material.clone(),
 		})
 	}
 }
 
+impl Bounded for Plane {
+	fn bounds(&self) -> Option<Aabb> {
+		None
+	}
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PlaneFormat {
 	pub origin: VectorFormat,
 	pub normal: VectorFormat,
+	#[serde(default = "default_tile_size")]
+	pub tile_size: f64,
 	pub material: MaterialFormat,
 }
 
-impl From<PlaneFormat> for Plane {
-    fn from(v: PlaneFormat) -> Plane { 
-        Plane {
-            origin: v.origin.into(),
-            normal: v.normal.into(),
-            material: v.material.into(),
-        }
-    }
+fn default_tile_size() -> f64 {
+	1.0
+}
+
+impl Plane {
+	pub fn load(v: PlaneFormat, context: &LoadContext) -> Result<Plane, SceneError> {
+		Ok(Plane {
+			origin: v.origin.into(),
+			normal: v.normal.into(),
+			tile_size: v.tile_size,
+			material: Arc::new(v.material.load(context)?),
+		})
+	}
+}
+
+impl TryFrom<PlaneFormat> for Plane {
+	type Error = SceneError;
+
+	fn try_from(v: PlaneFormat) -> Result<Plane, SceneError> {
+		Plane::load(v, &LoadContext::new(Path::new("")))
+	}
 }
 
 impl From<Plane> for PlaneFormat {
@@ -47,7 +90,8 @@ impl From<Plane> for PlaneFormat {
         PlaneFormat {
             origin: v.origin.into(),
 			normal: v.normal.into(),
-			material: v.material.into(),
+			tile_size: v.tile_size,
+			material: Arc::unwrap_or_clone(v.material).into(),
         }
     }
 }
\ No newline at end of file
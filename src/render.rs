@@ -0,0 +1,283 @@
+use cgmath::{Vector3, Vector4};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+use crate::{Scene, Camera, shade_ray, trace_path};
+
+/// Light transport algorithm used to shade camera rays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Integrator {
+	/// Direct lighting plus recursive mirror reflection and refraction, see `shade_ray`.
+	#[default]
+	Whitted,
+	/// Unbiased Monte Carlo path tracing, see `trace_path`.
+	PathTracing,
+}
+
+impl FromStr for Integrator {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Integrator, String> {
+		match s.to_ascii_lowercase().as_str() {
+			"whitted" => Ok(Integrator::Whitted),
+			"path" | "pathtracing" | "path_tracing" => Ok(Integrator::PathTracing),
+			_ => Err(format!("unknown integrator: {}", s)),
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+	pub width: u32,
+	pub height: u32,
+	pub num_samples: u32,
+	pub max_bounces: u32,
+	pub seed: u64,
+	/// Width and height of the square tiles the image is split into.
+	pub tile_size: u32,
+	/// Number of worker threads on native targets, 0 uses every core.
+	pub threads: usize,
+	pub integrator: Integrator,
+}
+
+impl Default for RenderSettings {
+	fn default() -> RenderSettings {
+		RenderSettings {
+			width: 512,
+			height: 512,
+			num_samples: 1,
+			max_bounces: 1,
+			seed: 0,
+			tile_size: 32,
+			threads: 0,
+			integrator: Integrator::Whitted,
+		}
+	}
+}
+
+/// Linear float RGBA framebuffer, stored row by row starting at the top left.
+#[derive(Debug, Clone)]
+pub struct Image {
+	pub width: u32,
+	pub height: u32,
+	pub pixels: Vec<Vector4<f64>>,
+}
+
+impl Image {
+	pub fn new(width: u32, height: u32) -> Image {
+		Image {
+			width,
+			height,
+			pixels: vec![Vector4::new(0.0, 0.0, 0.0, 1.0); pixel_count(width, height)],
+		}
+	}
+
+	pub fn get(&self, x: u32, y: u32) -> Vector4<f64> {
+		self.pixels[self.index(x, y)]
+	}
+
+	pub fn set(&mut self, x: u32, y: u32, color: Vector4<f64>) {
+		let index = self.index(x, y);
+		self.pixels[index] = color;
+	}
+
+	fn index(&self, x: u32, y: u32) -> usize {
+		y as usize * self.width as usize + x as usize
+	}
+
+	/// Clamps every channel to [0, 1], encodes the colors as sRGB, which image
+	/// viewers expect, and quantizes them to 8 bits. Alpha stays linear.
+	pub fn to_rgba8(&self) -> Vec<u8> {
+		let quantize = |v: f64| (255.0 * v.clamp(0.0, 1.0)).round() as u8;
+		self.pixels.iter().flat_map(|pixel| {
+			let color = pixel.truncate().map(|v| quantize(linear_to_srgb(v.clamp(0.0, 1.0))));
+			[color.x, color.y, color.z, quantize(pixel.w)]
+		}).collect()
+	}
+}
+
+/// Encodes a linear channel in `[0, 1]` as sRGB, the inverse of the decoding
+/// image textures do.
+fn linear_to_srgb(v: f64) -> f64 {
+	if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
+
+/// Number of pixels in a `width` by `height` image. Panics if that doesn't fit
+/// in memory, as on 32-bit targets it may not.
+fn pixel_count(width: u32, height: u32) -> usize {
+	(width as usize).checked_mul(height as usize).expect("image is too large")
+}
+
+/// Distance between neighbouring pixel centers in the `[0, 1]` camera
+/// coordinates, where the first and last pixels sit at 0 and 1.
+fn pixel_spacing(size: u32) -> f64 {
+	1.0 / (size.max(2) - 1) as f64
+}
+
+/// Camera coordinate of the center of pixel `i` out of `size`. A single pixel
+/// sits in the middle.
+fn pixel_coordinate(i: u32, size: u32) -> f64 {
+	if size > 1 { i as f64 * pixel_spacing(size) } else { 0.5 }
+}
+
+/// Axis aligned block of pixels, `x0..x1` by `y0..y1`.
+#[derive(Debug, Clone, Copy)]
+struct Tile {
+	x0: u32,
+	y0: u32,
+	x1: u32,
+	y1: u32,
+}
+
+fn tiles(width: u32, height: u32, tile_size: u32) -> Vec<Tile> {
+	let tile_size = tile_size.max(1);
+	let mut tiles = Vec::new();
+	for y0 in (0..height).step_by(tile_size as usize) {
+		for x0 in (0..width).step_by(tile_size as usize) {
+			tiles.push(Tile {
+				x0,
+				y0,
+				x1: (x0 + tile_size).min(width),
+				y1: (y0 + tile_size).min(height),
+			});
+		}
+	}
+	tiles
+}
+
+/// SplitMix64 finalizer, used to derive an independent RNG stream for every pixel.
+fn mix(mut z: u64) -> u64 {
+	z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+	z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+	z ^ (z >> 31)
+}
+
+fn pixel_rng(seed: u64, x: u32, y: u32) -> StdRng {
+	let pixel = ((y as u64) << 32) | x as u64;
+	StdRng::seed_from_u64(mix(mix(seed).wrapping_add(pixel)))
+}
+
+fn render_pixel(scene: &Scene, camera: &Camera, settings: &RenderSettings, x: u32, y: u32) -> Vector4<f64> {
+	let RenderSettings { width, height, num_samples, max_bounces, seed, integrator, .. } = *settings;
+	let mut rng = pixel_rng(seed, x, y);
+
+	let u = pixel_coordinate(x, width);
+	let v = pixel_coordinate(height - y - 1, height);
+
+	let spread = camera.pixel_spread(height);
+
+	let mut pixel_color = Vector3::new(0.0, 0.0, 0.0);
+	for i in 0..num_samples {
+		let du: f64 = rng.gen_range(-0.5..=0.5) * pixel_spacing(width);
+		let dv: f64 = rng.gen_range(-0.5..=0.5) * pixel_spacing(height);
+
+		let ray = if i == 0 { camera.get_ray(u, v, &mut rng) } else { camera.get_ray(u + du, v + dv, &mut rng) };
+		let Some(mut ray) = ray else { continue; };
+		ray.spread = spread;
+		pixel_color += match integrator {
+			Integrator::Whitted => shade_ray(scene, &ray, max_bounces, &mut rng).unwrap_or(Vector3::new(0.0, 0.0, 0.0)),
+			Integrator::PathTracing => trace_path(scene, &ray, max_bounces, &mut rng),
+		};
+	}
+	(pixel_color / num_samples as f64).extend(1.0)
+}
+
+fn render_tile(scene: &Scene, camera: &Camera, settings: &RenderSettings, tile: Tile) -> Vec<Vector4<f64>> {
+	let mut pixels = Vec::with_capacity(pixel_count(tile.x1 - tile.x0, tile.y1 - tile.y0));
+	for y in tile.y0..tile.y1 {
+		for x in tile.x0..tile.x1 {
+			pixels.push(render_pixel(scene, camera, settings, x, y));
+		}
+	}
+	pixels
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn render_tiles(scene: &Scene, camera: &Camera, settings: &RenderSettings, tiles: &[Tile]) -> Vec<Vec<Vector4<f64>>> {
+	use rayon::prelude::*;
+	let render = || tiles.par_iter().map(|tile| render_tile(scene, camera, settings, *tile)).collect();
+	match rayon::ThreadPoolBuilder::new().num_threads(settings.threads).build() {
+		Ok(pool) => pool.install(render),
+		Err(_) => render(),
+	}
+}
+
+#[cfg(target_arch = "wasm32")]
+fn render_tiles(scene: &Scene, camera: &Camera, settings: &RenderSettings, tiles: &[Tile]) -> Vec<Vec<Vector4<f64>>> {
+	tiles.iter().map(|tile| render_tile(scene, camera, settings, *tile)).collect()
+}
+
+/// Renders `scene` into a new image. On native targets the image is split into
+/// `settings.tile_size` tiles that are rendered in parallel; every pixel seeds its
+/// own RNG so the result does not depend on the number of threads.
+pub fn render(scene: &Scene, settings: &RenderSettings) -> Image {
+	let mut camera = scene.camera.clone();
+	camera.aspect = settings.width as f64 / settings.height as f64;
+	camera.update_view();
+
+	let tiles = tiles(settings.width, settings.height, settings.tile_size);
+	let mut image = Image::new(settings.width, settings.height);
+	for (tile, pixels) in tiles.iter().zip(render_tiles(scene, &camera, settings, &tiles)) {
+		let mut pixels = pixels.into_iter();
+		for y in tile.y0..tile.y1 {
+			for x in tile.x0..tile.x1 {
+				image.set(x, y, pixels.next().unwrap());
+			}
+		}
+	}
+	image
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::path::Path;
+
+	#[test]
+	fn result_does_not_depend_on_thread_count() {
+		let scene = Scene::from_json_with_base_dir(include_str!("../scenes/softshadows.json"), Path::new("scenes")).unwrap();
+		let render_with = |threads, integrator| render(&scene, &RenderSettings {
+			width: 23,
+			height: 17,
+			num_samples: 2,
+			max_bounces: 3,
+			seed: 7,
+			tile_size: 5,
+			threads,
+			integrator,
+		}).pixels;
+		for integrator in [Integrator::Whitted, Integrator::PathTracing] {
+			let single = render_with(1, integrator);
+			assert_eq!(single, render_with(3, integrator));
+			assert_eq!(single, render_with(8, integrator));
+		}
+	}
+
+	#[test]
+	fn rgba8_is_srgb_encoded() {
+		let mut image = Image::new(4, 1);
+		image.set(1, 0, Vector4::new(0.5, 0.214, 1.0, 1.0));
+		image.set(2, 0, Vector4::new(0.001, 2.0, -1.0, 0.5));
+		image.set(3, 0, Vector4::new(1.0, 1.0, 1.0, 1.0));
+		assert_eq!(image.to_rgba8(), vec![
+			0, 0, 0, 255,
+			188, 127, 255, 255,
+			3, 255, 0, 128,
+			255, 255, 255, 255,
+		]);
+	}
+
+	#[test]
+	fn single_pixel_rows_and_columns_are_finite() {
+		let scene = Scene::from_json_with_base_dir(include_str!("../scenes/spheres.json"), Path::new("scenes")).unwrap();
+		for (width, height) in [(1, 1), (1, 5), (5, 1)] {
+			let image = render(&scene, &RenderSettings { width, height, num_samples: 3, ..RenderSettings::default() });
+			assert_eq!(image.pixels.len(), (width * height) as usize);
+			assert!(image.pixels.iter().all(|pixel| pixel.x.is_finite() && pixel.y.is_finite() && pixel.z.is_finite()));
+		}
+		// The single pixel looks down the middle of the view, like the center of an odd sized image.
+		let center = render(&scene, &RenderSettings { width: 1, height: 1, ..RenderSettings::default() }).get(0, 0);
+		assert_eq!(center, render(&scene, &RenderSettings { width: 5, height: 5, ..RenderSettings::default() }).get(2, 2));
+	}
+}
@@ -0,0 +1,113 @@
+use std::f64::consts::PI;
+use cgmath::Vector3;
+use cgmath::InnerSpace;
+use rand::Rng;
+
+/// Two unit vectors that together with `n` form a right handed orthonormal basis.
+pub fn orthonormal_basis(n: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+	// Duff et al., "Building an Orthonormal Basis, Revisited"
+	let sign = 1.0_f64.copysign(n.z);
+	let a = -1.0 / (sign + n.z);
+	let b = n.x * n.y * a;
+	(
+		Vector3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+		Vector3::new(b, sign + n.y * n.y * a, -n.y),
+	)
+}
+
+/// Expresses a direction given in the local frame around `n` (z up) in world space.
+pub fn to_world(local: Vector3<f64>, n: Vector3<f64>) -> Vector3<f64> {
+	let (t, b) = orthonormal_basis(n);
+	t * local.x + b * local.y + n * local.z
+}
+
+/// Cosine weighted direction in the hemisphere around the unit normal `n`,
+/// with density `cos(theta) / PI`.
+pub fn cosine_hemisphere<R: Rng>(n: Vector3<f64>, rng: &mut R) -> Vector3<f64> {
+	let u1: f64 = rng.gen();
+	let u2: f64 = rng.gen();
+	let r = u1.sqrt();
+	let phi = 2.0 * PI * u2;
+	let local = Vector3::new(r * phi.cos(), r * phi.sin(), (1.0 - u1).max(0.0).sqrt());
+	to_world(local, n).normalize()
+}
+
+/// Direction around the unit axis `n` distributed as `cos(theta)^exponent`,
+/// with density `(exponent + 1) / (2 PI) * cos(theta)^exponent`.
+pub fn power_cosine<R: Rng>(n: Vector3<f64>, exponent: f64, rng: &mut R) -> Vector3<f64> {
+	let u1: f64 = rng.gen();
+	let u2: f64 = rng.gen();
+	let cos_theta = u1.powf(1.0 / (exponent + 1.0));
+	let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+	let phi = 2.0 * PI * u2;
+	let local = Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+	to_world(local, n).normalize()
+}
+
+/// Microfacet normal around the unit normal `n` drawn from the GGX distribution
+/// with roughness `alpha` as seen from the unit direction `wo`, so facets facing
+/// away from `wo` or hidden behind others are never picked. The density is
+/// `G1(wo) * max(0, wo.h) * D(h) / n.wo`.
+pub fn ggx_visible_normal<R: Rng>(n: Vector3<f64>, wo: Vector3<f64>, alpha: f64, rng: &mut R) -> Vector3<f64> {
+	// Heitz, "Sampling the GGX Distribution of Visible Normals"
+	let (t, b) = orthonormal_basis(n);
+	let local = Vector3::new(cgmath::dot(wo, t), cgmath::dot(wo, b), cgmath::dot(wo, n));
+	// Stretch to the hemisphere configuration, where the distribution is uniform.
+	let v = Vector3::new(alpha * local.x, alpha * local.y, local.z).normalize();
+	let length2 = v.x * v.x + v.y * v.y;
+	let t1 = if length2 > 0.0 { Vector3::new(-v.y, v.x, 0.0) / length2.sqrt() } else { Vector3::new(1.0, 0.0, 0.0) };
+	let t2 = v.cross(t1);
+	let (x, y) = uniform_disk(rng);
+	let s = 0.5 * (1.0 + v.z);
+	let y = (1.0 - s) * (1.0 - x * x).max(0.0).sqrt() + s * y;
+	let h = t1 * x + t2 * y + v * (1.0 - x * x - y * y).max(0.0).sqrt();
+	let h = Vector3::new(alpha * h.x, alpha * h.y, h.z.max(0.0)).normalize();
+	(t * h.x + b * h.y + n * h.z).normalize()
+}
+
+/// Uniformly distributed point on the unit disk.
+pub fn uniform_disk<R: Rng>(rng: &mut R) -> (f64, f64) {
+	let r = rng.gen::<f64>().sqrt();
+	let phi = 2.0 * PI * rng.gen::<f64>();
+	(r * phi.cos(), r * phi.sin())
+}
+
+/// Uniformly distributed point in the regular polygon with `sides` corners on the
+/// unit circle, the first at angle `rotation`.
+pub fn uniform_polygon<R: Rng>(sides: u32, rotation: f64, rng: &mut R) -> (f64, f64) {
+	// Pick one of the triangles fanning out from the center, then a point in it.
+	let sector = rng.gen_range(0..sides) as f64;
+	let a = rotation + 2.0 * PI * sector / sides as f64;
+	let b = rotation + 2.0 * PI * (sector + 1.0) / sides as f64;
+	let (mut s, mut t) = (rng.gen::<f64>(), rng.gen::<f64>());
+	if s + t > 1.0 {
+		(s, t) = (1.0 - s, 1.0 - t);
+	}
+	(s * a.cos() + t * b.cos(), s * a.sin() + t * b.sin())
+}
+
+/// Uniformly distributed direction on the unit sphere.
+pub fn uniform_sphere<R: Rng>(rng: &mut R) -> Vector3<f64> {
+	let z = 1.0 - 2.0 * rng.gen::<f64>();
+	let r = (1.0 - z * z).max(0.0).sqrt();
+	let phi = 2.0 * PI * rng.gen::<f64>();
+	Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Number in `[0, 1)` that looks uniformly random but only depends on `values`,
+/// for where there is no random number generator at hand.
+pub fn hash_to_unit(values: &[f64]) -> f64 {
+	// SplitMix64's finalizer, applied after mixing in each value.
+	let mut h: u64 = 0x9e3779b97f4a7c15;
+	for v in values {
+		h ^= v.to_bits();
+		h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+		h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+		h ^= h >> 31;
+	}
+	(h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+pub fn luminance(color: Vector3<f64>) -> f64 {
+	0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
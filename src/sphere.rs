@@ -1,19 +1,23 @@
 use serde::{Serialize, Deserialize};
-use cgmath::{Point3};
+use cgmath::{Point3, Vector3};
 use cgmath::InnerSpace;
-use crate::{Ray, Hit, Intersect, Material, VectorFormat, MaterialFormat};
+use std::path::Path;
+use std::sync::Arc;
+use crate::{Ray, Hit, Intersect, Material, VectorFormat, MaterialFormat, Aabb, Bounded, Keyframes, LoadContext, SceneError, spherical_uv, orthogonal_tangent, SPHERE_UV_DENSITY};
 
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(from = "SphereFormat", into = "SphereFormat")]
+#[serde(try_from = "SphereFormat", into = "SphereFormat")]
 pub struct Sphere {
-	pub origin: Point3<f64>,
+	/// Center of the sphere over time.
+	pub origin: Keyframes<Point3<f64>>,
 	pub radius: f64,
-	pub material: Material,
+	pub material: Arc<Material>,
 }
 
 impl Intersect for Sphere {
 	fn intersect(&self, ray: &Ray) -> Option<Hit> {
-		let pc = ray.origin - self.origin;
+		let origin = self.origin.at(ray.time);
+		let pc = ray.origin - origin;
 		let a = cgmath::dot(ray.direction, ray.direction);
 		let b = 2.0 * cgmath::dot(ray.direction, pc);
 		let c = cgmath::dot(pc, pc) - (self.radius * self.radius);
@@ -21,42 +25,86 @@ impl Intersect for Sphere {
 		if d < 0.0 { return None };
 		let t_plus = (-b + d.sqrt()) / (2.0*a);
 		let t_minus = (-b - d.sqrt()) / (2.0*a);
-		let distance = t_plus.min(t_minus);
+		let distance = if t_minus >= 0.0 { t_minus } else { t_plus };
 		if distance < 0.0 { return None };
 		let position = ray.at(distance);
-		let normal = (position - self.origin).normalize();
+		let normal = (position - origin).normalize();
+		let (uv, tangent) = spherical_uv(normal);
+		let tangent = orthogonal_tangent(tangent, normal);
 		Some(Hit {
 			distance,
 			position,
 			normal,
+			uv,
+			tangent,
+			bitangent: normal.cross(tangent),
+			uv_density: SPHERE_UV_DENSITY / self.radius,
 			material: self.material.clone(),
 		})
 	}
 }
 
+impl Bounded for Sphere {
+	fn bounds(&self) -> Option<Aabb> {
+		// The center moves in straight lines between keyframes, so the boxes
+		// around the keyframes cover the whole path.
+		let radius = Vector3::new(self.radius, self.radius, self.radius);
+		Some(self.origin.keyframes().iter().fold(Aabb::empty(), |bounds, (_, origin)| {
+			bounds.union(&Aabb::new(origin - radius, origin + radius))
+		}))
+	}
+}
+
+/// Where a moving sphere's center is at `time`.
+#[derive(Serialize, Deserialize)]
+pub struct SphereKeyframeFormat {
+	pub time: f64,
+	pub origin: VectorFormat,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SphereFormat {
+	/// Center at time 0.
 	pub origin: VectorFormat,
 	pub radius: f64,
 	pub material: MaterialFormat,
+	/// Centers at other times, for a moving sphere.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub motion: Vec<SphereKeyframeFormat>,
 }
 
-impl From<SphereFormat> for Sphere {
-    fn from(v: SphereFormat) -> Sphere { 
-        Sphere {
-            origin: v.origin.into(),
-            radius: v.radius,
-            material: v.material.into(),
-        }
-    }
+impl Sphere {
+	pub fn load(v: SphereFormat, context: &LoadContext) -> Result<Sphere, SceneError> {
+		let keyframes = std::iter::once((0.0, v.origin.into()))
+			.chain(v.motion.into_iter().map(|keyframe| (keyframe.time, keyframe.origin.into())))
+			.collect();
+		Ok(Sphere {
+			origin: Keyframes::new(keyframes),
+			radius: v.radius,
+			material: Arc::new(v.material.load(context)?),
+		})
+	}
+}
+
+impl TryFrom<SphereFormat> for Sphere {
+	type Error = SceneError;
+
+	fn try_from(v: SphereFormat) -> Result<Sphere, SceneError> {
+		Sphere::load(v, &LoadContext::new(Path::new("")))
+	}
 }
 
 impl From<Sphere> for SphereFormat {
     fn from(v: Sphere) -> SphereFormat { 
+		let origin = v.origin.at(0.0);
         SphereFormat {
-            origin: v.origin.into(),
+            origin: origin.into(),
 			radius: v.radius,
-			material: v.material.into(),
+			material: Arc::unwrap_or_clone(v.material).into(),
+			motion: v.origin.keyframes().iter()
+				.filter(|(time, _)| *time != 0.0)
+				.map(|&(time, origin)| SphereKeyframeFormat { time, origin: origin.into() })
+				.collect(),
         }
     }
 }
\ No newline at end of file
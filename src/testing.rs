@@ -0,0 +1,50 @@
+use cgmath::{Point3, Vector3, InnerSpace, EuclideanSpace};
+use rand::Rng;
+use rand::rngs::StdRng;
+use serde_json::{json, Value};
+use crate::{Ray, Scene, SceneError};
+
+/// Vector with each component uniform in `-size..size`.
+pub fn random_vector(rng: &mut StdRng, size: f64) -> Vector3<f64> {
+	Vector3::new(rng.gen_range(-size..size), rng.gen_range(-size..size), rng.gen_range(-size..size))
+}
+
+/// Ray starting anywhere in a cube `2 * size` wide around the origin, pointing
+/// anywhere.
+pub fn random_ray(rng: &mut StdRng, size: f64) -> Ray {
+	let origin = Point3::from_vec(random_vector(rng, size));
+	let direction = random_vector(rng, 1.0).normalize();
+	Ray { origin, direction, spread: 0.0, time: 0.0 }
+}
+
+/// A scene with a camera at z = 5 looking down -z and nothing else, with the
+/// entries of `extra` added or replaced.
+pub fn scene_json(extra: Value) -> Value {
+	let mut scene = json!({
+		"camera": { "origin": { "x": 0, "y": 0, "z": 5 }, "fovy": 0.8 },
+		"lights": [],
+		"objects": [],
+	});
+	for (key, value) in extra.as_object().expect("extra scene entries") {
+		scene[key] = value.clone();
+	}
+	scene
+}
+
+/// Loads `scene_json(extra)`.
+pub fn load_scene(extra: Value) -> Result<Scene, SceneError> {
+	Scene::from_json(&scene_json(extra).to_string())
+}
+
+/// A unit sphere at the origin made of `material`.
+pub fn sphere(material: Value) -> Value {
+	json!({ "type": "Sphere", "origin": { "x": 0, "y": 0, "z": 0 }, "radius": 1, "material": material })
+}
+
+/// Expects `scene` to have failed to load with an error that prints as
+/// `message`.
+pub fn expect_error(scene: Result<Scene, SceneError>, message: &str) -> SceneError {
+	let error = scene.err().expect("scene should fail to load");
+	assert_eq!(error.to_string(), message);
+	error
+}
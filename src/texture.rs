@@ -0,0 +1,462 @@
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::{self, Visitor, MapAccess, value::MapAccessDeserializer};
+use std::fmt;
+use cgmath::{Point3, Vector2, Vector3};
+use cgmath::EuclideanSpace;
+use std::f64::consts::PI;
+use std::sync::Arc;
+use crate::{RgbFormat, LoadContext, SceneError, ImageTexture, TextureFilter, TextureWrap};
+use crate::noise::{perlin, fbm, turbulence};
+
+/// Which coordinates a procedural texture is evaluated at. `Uv` textures
+/// follow the surface parameterization, `Position` textures are solid and
+/// carved out of world space.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum TextureMapping {
+	#[default]
+	Uv,
+	Position,
+}
+
+/// A color that can vary over a surface, looked up from a hit's texture
+/// coordinates or position.
+///
+/// Procedural textures blend between two nested textures `a` and `b`, and
+/// multiply their coordinates by `scale` first, so larger scales give smaller
+/// features.
+#[derive(Debug, Clone)]
+pub enum Texture {
+	Constant(Vector3<f64>),
+	/// One of the scene's named textures, shared by every material using it.
+	Named {
+		name: String,
+		texture: Arc<Texture>,
+	},
+	/// A bitmap, repeated `scale` times per unit of texture coordinates.
+	Image {
+		image: Arc<ImageTexture>,
+		scale: f64,
+	},
+	/// Shows the fractional part of the texture coordinates as red and green,
+	/// useful to check how a surface is parameterized.
+	Uv,
+	/// Alternates between `a` and `b` on a grid of unit squares, or unit cubes
+	/// with `Position` mapping.
+	Checker {
+		a: Box<Texture>,
+		b: Box<Texture>,
+		scale: f64,
+		mapping: TextureMapping,
+	},
+	/// Alternating bands along the first coordinate.
+	Stripes {
+		a: Box<Texture>,
+		b: Box<Texture>,
+		scale: f64,
+		mapping: TextureMapping,
+	},
+	/// Linear blend from `a` to `b` as the second coordinate goes from 0 to 1.
+	Gradient {
+		a: Box<Texture>,
+		b: Box<Texture>,
+		scale: f64,
+		mapping: TextureMapping,
+	},
+	/// Perlin noise with `octaves` layers of detail.
+	Noise {
+		a: Box<Texture>,
+		b: Box<Texture>,
+		scale: f64,
+		octaves: u32,
+		mapping: TextureMapping,
+	},
+	/// Veins along the first coordinate, distorted by `turbulence`.
+	Marble {
+		a: Box<Texture>,
+		b: Box<Texture>,
+		scale: f64,
+		octaves: u32,
+		turbulence: f64,
+		mapping: TextureMapping,
+	},
+	/// Rings around the second axis, distorted by `turbulence`.
+	Wood {
+		a: Box<Texture>,
+		b: Box<Texture>,
+		scale: f64,
+		octaves: u32,
+		turbulence: f64,
+		mapping: TextureMapping,
+	},
+}
+
+fn coordinates(mapping: TextureMapping, scale: f64, uv: Vector2<f64>, position: Point3<f64>) -> Vector3<f64> {
+	match mapping {
+		TextureMapping::Uv => uv.extend(0.0) * scale,
+		TextureMapping::Position => position.to_vec() * scale,
+	}
+}
+
+fn blend(a: &Texture, b: &Texture, t: f64, uv: Vector2<f64>, position: Point3<f64>, footprint: f64) -> Vector3<f64> {
+	let t = t.clamp(0.0, 1.0);
+	a.evaluate(uv, position, footprint) * (1.0 - t) + b.evaluate(uv, position, footprint) * t
+}
+
+impl Texture {
+	/// Color at texture coordinates `uv` and world `position`. `footprint` is the
+	/// approximate width in texture coordinates of the surface a ray covers,
+	/// which image textures use to pick a mip level.
+	pub fn evaluate(&self, uv: Vector2<f64>, position: Point3<f64>, footprint: f64) -> Vector3<f64> {
+		match self {
+			Texture::Constant(color) => *color,
+			Texture::Named { texture, .. } => texture.evaluate(uv, position, footprint),
+			Texture::Image { image, scale } => image.lookup(uv * *scale, footprint * scale),
+			Texture::Uv => Vector3::new(uv.x.rem_euclid(1.0), uv.y.rem_euclid(1.0), 0.0),
+			Texture::Checker { a, b, scale, mapping } => {
+				let p = coordinates(*mapping, *scale, uv, position);
+				let cell = p.x.floor() + p.y.floor() + p.z.floor();
+				if cell.rem_euclid(2.0) < 1.0 { a.evaluate(uv, position, footprint) } else { b.evaluate(uv, position, footprint) }
+			}
+			Texture::Stripes { a, b, scale, mapping } => {
+				let p = coordinates(*mapping, *scale, uv, position);
+				if p.x.floor().rem_euclid(2.0) < 1.0 { a.evaluate(uv, position, footprint) } else { b.evaluate(uv, position, footprint) }
+			}
+			Texture::Gradient { a, b, scale, mapping } => {
+				let p = coordinates(*mapping, *scale, uv, position);
+				blend(a, b, p.y, uv, position, footprint)
+			}
+			Texture::Noise { a, b, scale, octaves, mapping } => {
+				let p = coordinates(*mapping, *scale, uv, position);
+				let t = if *octaves <= 1 { perlin(p) } else { fbm(p, *octaves) };
+				blend(a, b, 0.5 + 0.5 * t, uv, position, footprint)
+			}
+			Texture::Marble { a, b, scale, octaves, turbulence: amount, mapping } => {
+				let p = coordinates(*mapping, *scale, uv, position);
+				let t = 0.5 + 0.5 * ((p.x + amount * turbulence(p, *octaves)) * PI).sin();
+				blend(a, b, t, uv, position, footprint)
+			}
+			Texture::Wood { a, b, scale, octaves, turbulence: amount, mapping } => {
+				let p = coordinates(*mapping, *scale, uv, position);
+				let r = (p.x * p.x + p.z * p.z).sqrt() + amount * fbm(p, *octaves);
+				blend(a, b, r.rem_euclid(1.0), uv, position, footprint)
+			}
+		}
+	}
+
+	/// Whether any image this texture reads, directly or through names, is decoded as sRGB.
+	fn has_srgb_image(&self) -> bool {
+		match self {
+			Texture::Constant(_) | Texture::Uv => false,
+			Texture::Named { texture, .. } => texture.has_srgb_image(),
+			Texture::Image { image, .. } => image.srgb,
+			Texture::Checker { a, b, .. } | Texture::Stripes { a, b, .. } | Texture::Gradient { a, b, .. }
+				| Texture::Noise { a, b, .. } | Texture::Marble { a, b, .. } | Texture::Wood { a, b, .. } => a.has_srgb_image() || b.has_srgb_image(),
+		}
+	}
+}
+
+impl From<Vector3<f64>> for Texture {
+	fn from(color: Vector3<f64>) -> Texture {
+		Texture::Constant(color)
+	}
+}
+
+/// A plain color, a number standing for a grey of that brightness, the name of
+/// one of the scene's textures, or a texture object tagged with its `type`.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum TextureFormat {
+	Constant(RgbFormat),
+	Scalar(f64),
+	Named(String),
+	Texture(TaggedTextureFormat),
+}
+
+// Hand-written for the same reason as `MaterialFormat`'s.
+impl<'de> Deserialize<'de> for TextureFormat {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TextureFormat, D::Error> {
+		struct TextureVisitor;
+
+		impl<'de> Visitor<'de> for TextureVisitor {
+			type Value = TextureFormat;
+
+			fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+				f.write_str("a color, a number, a texture or the name of one")
+			}
+
+			fn visit_f64<E: de::Error>(self, v: f64) -> Result<TextureFormat, E> {
+				Ok(TextureFormat::Scalar(v))
+			}
+
+			fn visit_i64<E: de::Error>(self, v: i64) -> Result<TextureFormat, E> {
+				Ok(TextureFormat::Scalar(v as f64))
+			}
+
+			fn visit_u64<E: de::Error>(self, v: u64) -> Result<TextureFormat, E> {
+				Ok(TextureFormat::Scalar(v as f64))
+			}
+
+			fn visit_str<E: de::Error>(self, v: &str) -> Result<TextureFormat, E> {
+				Ok(TextureFormat::Named(v.to_string()))
+			}
+
+			fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<TextureFormat, A::Error> {
+				// Only texture objects have a `type`, anything else is read as a color.
+				let value = serde_json::Value::deserialize(MapAccessDeserializer::new(map))?;
+				let texture = if value.get("type").is_some() {
+					TaggedTextureFormat::deserialize(value).map(TextureFormat::Texture)
+				} else {
+					RgbFormat::deserialize(value).map(TextureFormat::Constant)
+				};
+				texture.map_err(de::Error::custom)
+			}
+		}
+
+		deserializer.deserialize_any(TextureVisitor)
+	}
+}
+
+fn default_scale() -> f64 {
+	1.0
+}
+
+fn default_octaves() -> u32 {
+	4
+}
+
+fn default_turbulence() -> f64 {
+	1.0
+}
+
+fn position_mapping() -> TextureMapping {
+	TextureMapping::Position
+}
+
+fn is_false(v: &bool) -> bool {
+	!*v
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum TaggedTextureFormat {
+	Uv,
+	/// PNG or JPEG file, resolved relative to the scene file.
+	Image {
+		filename: String,
+		#[serde(default = "default_scale")]
+		scale: f64,
+		#[serde(default)]
+		filter: TextureFilter,
+		#[serde(default)]
+		wrap: TextureWrap,
+		/// Whether the file stores sRGB colors, as photos and painted textures
+		/// usually do. Defaults to true, except in normal and bump maps.
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		srgb: Option<bool>,
+		/// Read the alpha channel as grey instead of the colors, as opacity masks do.
+		#[serde(default, skip_serializing_if = "is_false")]
+		alpha: bool,
+	},
+	Checker {
+		a: Box<TextureFormat>,
+		b: Box<TextureFormat>,
+		#[serde(default = "default_scale")]
+		scale: f64,
+		#[serde(default)]
+		mapping: TextureMapping,
+	},
+	Stripes {
+		a: Box<TextureFormat>,
+		b: Box<TextureFormat>,
+		#[serde(default = "default_scale")]
+		scale: f64,
+		#[serde(default)]
+		mapping: TextureMapping,
+	},
+	Gradient {
+		a: Box<TextureFormat>,
+		b: Box<TextureFormat>,
+		#[serde(default = "default_scale")]
+		scale: f64,
+		#[serde(default)]
+		mapping: TextureMapping,
+	},
+	Noise {
+		a: Box<TextureFormat>,
+		b: Box<TextureFormat>,
+		#[serde(default = "default_scale")]
+		scale: f64,
+		#[serde(default = "default_octaves")]
+		octaves: u32,
+		#[serde(default = "position_mapping")]
+		mapping: TextureMapping,
+	},
+	Marble {
+		a: Box<TextureFormat>,
+		b: Box<TextureFormat>,
+		#[serde(default = "default_scale")]
+		scale: f64,
+		#[serde(default = "default_octaves")]
+		octaves: u32,
+		#[serde(default = "default_turbulence")]
+		turbulence: f64,
+		#[serde(default = "position_mapping")]
+		mapping: TextureMapping,
+	},
+	Wood {
+		a: Box<TextureFormat>,
+		b: Box<TextureFormat>,
+		#[serde(default = "default_scale")]
+		scale: f64,
+		#[serde(default = "default_octaves")]
+		octaves: u32,
+		#[serde(default = "default_turbulence")]
+		turbulence: f64,
+		#[serde(default = "position_mapping")]
+		mapping: TextureMapping,
+	},
+}
+
+fn boxed(v: TextureFormat, context: &LoadContext, srgb: bool) -> Result<Box<Texture>, SceneError> {
+	Ok(Box::new(v.load_with_srgb(context, srgb)?))
+}
+
+fn boxed_format(v: Texture) -> Box<TextureFormat> {
+	Box::new(v.into())
+}
+
+impl TextureFormat {
+	/// Builds the texture, looking up names in `context` and loading image files through it.
+	pub fn load(self, context: &LoadContext) -> Result<Texture, SceneError> {
+		self.load_with_srgb(context, true)
+	}
+
+	/// Builds a normal or bump map. Its images are linear unless they say
+	/// otherwise, and sRGB ones are rejected, including named textures.
+	pub fn load_map(self, context: &LoadContext) -> Result<Texture, SceneError> {
+		let texture = self.load_with_srgb(context, false)?;
+		if texture.has_srgb_image() {
+			return Err(SceneError::SrgbMap);
+		}
+		Ok(texture)
+	}
+
+	/// `srgb_default` decides how images that don't set `srgb` are decoded.
+	fn load_with_srgb(self, context: &LoadContext, srgb_default: bool) -> Result<Texture, SceneError> {
+		let v = match self {
+			TextureFormat::Constant(color) => return Ok(Texture::Constant(color.into())),
+			TextureFormat::Scalar(value) => return Ok(Texture::Constant(Vector3::new(value, value, value))),
+			TextureFormat::Named(name) => {
+				let texture = context.textures.get(&name).ok_or_else(|| SceneError::UnknownTexture { name: name.clone() })?;
+				return Ok(Texture::Named { name, texture: texture.clone() });
+			}
+			TextureFormat::Texture(v) => v,
+		};
+		Ok(match v {
+			TaggedTextureFormat::Uv => Texture::Uv,
+			TaggedTextureFormat::Image { filename, scale, filter, wrap, srgb, alpha } => Texture::Image {
+				image: Arc::new(ImageTexture::load(&filename, context, filter, wrap, srgb.unwrap_or(srgb_default), alpha)?),
+				scale,
+			},
+			TaggedTextureFormat::Checker { a, b, scale, mapping } => Texture::Checker { a: boxed(*a, context, srgb_default)?, b: boxed(*b, context, srgb_default)?, scale, mapping },
+			TaggedTextureFormat::Stripes { a, b, scale, mapping } => Texture::Stripes { a: boxed(*a, context, srgb_default)?, b: boxed(*b, context, srgb_default)?, scale, mapping },
+			TaggedTextureFormat::Gradient { a, b, scale, mapping } => Texture::Gradient { a: boxed(*a, context, srgb_default)?, b: boxed(*b, context, srgb_default)?, scale, mapping },
+			TaggedTextureFormat::Noise { a, b, scale, octaves, mapping } => Texture::Noise { a: boxed(*a, context, srgb_default)?, b: boxed(*b, context, srgb_default)?, scale, octaves, mapping },
+			TaggedTextureFormat::Marble { a, b, scale, octaves, turbulence, mapping } => Texture::Marble { a: boxed(*a, context, srgb_default)?, b: boxed(*b, context, srgb_default)?, scale, octaves, turbulence, mapping },
+			TaggedTextureFormat::Wood { a, b, scale, octaves, turbulence, mapping } => Texture::Wood { a: boxed(*a, context, srgb_default)?, b: boxed(*b, context, srgb_default)?, scale, octaves, turbulence, mapping },
+		})
+	}
+}
+
+impl From<Texture> for TextureFormat {
+	fn from(v: Texture) -> TextureFormat {
+		TextureFormat::Texture(match v {
+			Texture::Constant(color) => return TextureFormat::Constant(color.into()),
+			Texture::Named { name, .. } => return TextureFormat::Named(name),
+			Texture::Uv => TaggedTextureFormat::Uv,
+			Texture::Image { image, scale } => TaggedTextureFormat::Image {
+				filename: image.filename.clone(),
+				scale,
+				filter: image.filter,
+				wrap: image.wrap,
+				srgb: Some(image.srgb),
+				alpha: image.alpha,
+			},
+			Texture::Checker { a, b, scale, mapping } => TaggedTextureFormat::Checker { a: boxed_format(*a), b: boxed_format(*b), scale, mapping },
+			Texture::Stripes { a, b, scale, mapping } => TaggedTextureFormat::Stripes { a: boxed_format(*a), b: boxed_format(*b), scale, mapping },
+			Texture::Gradient { a, b, scale, mapping } => TaggedTextureFormat::Gradient { a: boxed_format(*a), b: boxed_format(*b), scale, mapping },
+			Texture::Noise { a, b, scale, octaves, mapping } => TaggedTextureFormat::Noise { a: boxed_format(*a), b: boxed_format(*b), scale, octaves, mapping },
+			Texture::Marble { a, b, scale, octaves, turbulence, mapping } => TaggedTextureFormat::Marble { a: boxed_format(*a), b: boxed_format(*b), scale, octaves, turbulence, mapping },
+			Texture::Wood { a, b, scale, octaves, turbulence, mapping } => TaggedTextureFormat::Wood { a: boxed_format(*a), b: boxed_format(*b), scale, octaves, turbulence, mapping },
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::{json, Value};
+	use crate::{Scene, SceneError, Material};
+	use crate::testing::{scene_json, load_scene, sphere, expect_error};
+	use super::*;
+
+	fn parse(json: &str) -> Result<TextureFormat, String> {
+		serde_json::from_str(json).map_err(|error| error.to_string())
+	}
+
+	fn error(json: &str) -> String {
+		parse(json).err().expect("texture should fail to parse")
+	}
+
+	#[test]
+	fn parses_every_form() {
+		assert!(matches!(parse("0.5"), Ok(TextureFormat::Scalar(v)) if v == 0.5));
+		assert!(matches!(parse("2"), Ok(TextureFormat::Scalar(v)) if v == 2.0));
+		assert!(matches!(parse(r#""brick""#), Ok(TextureFormat::Named(ref name)) if name == "brick"));
+		assert!(matches!(parse(r#"{ "r": 1, "g": 0.5, "b": 0 }"#), Ok(TextureFormat::Constant(RgbFormat { g, .. })) if g == 0.5));
+		assert!(matches!(parse(r#"{ "type": "Checker", "a": 0, "b": "brick" }"#), Ok(TextureFormat::Texture(TaggedTextureFormat::Checker { .. }))));
+	}
+
+	#[test]
+	fn errors_name_the_problem() {
+		assert!(error(r#"{ "type": "Chekcer", "a": 0, "b": 1 }"#).starts_with("unknown variant `Chekcer`, expected one of `Uv`, `Image`"));
+		assert!(error(r#"{ "type": "Checker", "a": 0 }"#).starts_with("missing field `b`"));
+		assert!(error(r#"{ "type": "Checker", "a": 0, "b": { "type": "Stripes", "a": 0, "b": [] } }"#).contains("a color, a number, a texture or the name of one"));
+		assert!(error(r#"{ "r": 1, "g": 1 }"#).starts_with("missing field `b`"));
+		assert!(error("[1, 2, 3]").starts_with("invalid type: sequence, expected a color, a number, a texture or the name of one"));
+	}
+
+	#[test]
+	fn srgb_map() {
+		let grid = json!({ "type": "Image", "filename": "grid.png" });
+		let files = [(String::from("grid.png"), include_bytes!("../textures/grid.png").to_vec())].into_iter().collect();
+		let load = |scene: Value| Scene::from_json_with_files(&scene.to_string(), &files);
+		let bump = |map: Value| json!({ "Bump": { "material": "Mirror", "map": map, "depth": 0.1 } });
+
+		// Inline maps are read as linear, named textures default to sRGB.
+		let scene = load(scene_json(json!({ "objects": [sphere(bump(grid.clone()))] }))).unwrap();
+		assert!(matches!(*scene.objects.iter().next().unwrap().material(), Material::Bump { map: Texture::Image { ref image, .. }, .. } if !image.srgb));
+
+		let named = scene_json(json!({ "textures": { "grid": grid }, "objects": [sphere(bump(json!("grid")))] }));
+		let error = expect_error(load(named), "objects[0]: normal and bump maps hold linear data, set \"srgb\": false on their image textures");
+		assert_eq!(error, SceneError::Object { index: 0, error: Box::new(SceneError::SrgbMap) });
+	}
+
+	#[test]
+	fn missing_image_file() {
+		let error = load_scene(json!({ "textures": { "wall": { "type": "Image", "filename": "missing.png" } } })).err().unwrap();
+		assert!(matches!(error, SceneError::Texture { ref name, error: ref inner } if name == "wall" && matches!(**inner, SceneError::ImageLoad { .. })));
+		assert!(error.to_string().starts_with("textures[\"wall\"]: failed to load image missing.png: "));
+	}
+
+	#[test]
+	fn unknown_texture() {
+		let material = json!({ "BlinnPhong": { "ambient": 0, "diffuse": "brick", "specular": 0, "intensity": 1 } });
+		let error = expect_error(load_scene(json!({ "objects": [sphere(material.clone())] })),
+			"objects[0]: unknown texture \"brick\", named textures must be listed in the scene's textures");
+		assert_eq!(error, SceneError::Object { index: 0, error: Box::new(SceneError::UnknownTexture { name: String::from("brick") }) });
+
+		let error = expect_error(load_scene(json!({ "materials": { "wall": material } })),
+			"materials[\"wall\"]: unknown texture \"brick\", named textures must be listed in the scene's textures");
+		assert!(matches!(error, SceneError::Material { .. }));
+	}
+}
@@ -0,0 +1,424 @@
+use std::borrow::Cow;
+use std::fmt;
+use serde::{Serialize, Deserialize};
+use cgmath::{Point3, Vector3, Matrix4, Quaternion, Rad};
+use cgmath::{Matrix, SquareMatrix, InnerSpace, EuclideanSpace, VectorSpace, Rotation3, Transform as _};
+use crate::{Ray, Aabb, VectorFormat};
+
+/// An affine object to world transform together with its cached inverse and
+/// inverse transpose, so transformed primitives don't have to invert per ray.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+	matrix: Matrix4<f64>,
+	inverse: Matrix4<f64>,
+	inverse_transpose: Matrix4<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransformError {
+	pub determinant: f64,
+}
+
+impl fmt::Display for TransformError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "transform is not invertible, determinant is {} (check for a zero scale component or a zero rotation axis)", self.determinant)
+	}
+}
+
+impl std::error::Error for TransformError {}
+
+const MIN_DETERMINANT: f64 = 1e-12;
+
+impl Transform {
+	pub fn new(matrix: Matrix4<f64>) -> Result<Transform, TransformError> {
+		let determinant = matrix.determinant();
+		if !determinant.is_finite() || determinant.abs() < MIN_DETERMINANT {
+			return Err(TransformError { determinant });
+		}
+		let inverse = matrix.invert().ok_or(TransformError { determinant })?;
+		Ok(Transform {
+			matrix,
+			inverse,
+			inverse_transpose: inverse.transpose(),
+		})
+	}
+
+	/// Translation * rotation * scale, the order used by every transformed primitive.
+	/// A zero `rot_axis` is accepted as long as `rot_angle` is zero too.
+	pub fn from_components(origin: Point3<f64>, scale: Vector3<f64>, rot_axis: Vector3<f64>, rot_angle: Rad<f64>) -> Result<Transform, TransformError> {
+		let rotation = if rot_angle.0 == 0.0 {
+			Matrix4::identity()
+		} else {
+			Matrix4::from_axis_angle(rot_axis.normalize(), rot_angle)
+		};
+		Transform::new(
+			Matrix4::from_translation(origin.to_vec())
+				* rotation
+				* Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z)
+		)
+	}
+
+	pub fn matrix(&self) -> &Matrix4<f64> {
+		&self.matrix
+	}
+
+	pub fn inverse(&self) -> &Matrix4<f64> {
+		&self.inverse
+	}
+
+	pub fn inverse_transpose(&self) -> &Matrix4<f64> {
+		&self.inverse_transpose
+	}
+
+	/// Geometric mean of the scale factors, how much lengths grow on average.
+	pub fn mean_scale(&self) -> f64 {
+		self.matrix.determinant().abs().cbrt()
+	}
+
+	pub fn point_to_world(&self, point: Point3<f64>) -> Point3<f64> {
+		self.matrix.transform_point(point)
+	}
+
+	pub fn vector_to_world(&self, vector: Vector3<f64>) -> Vector3<f64> {
+		self.matrix.transform_vector(vector)
+	}
+
+	pub fn normal_to_world(&self, normal: Vector3<f64>) -> Vector3<f64> {
+		self.inverse_transpose.transform_vector(normal).normalize()
+	}
+
+	/// Moves `ray` into object space. The direction is left unnormalized so
+	/// distances along the object space ray match those along `ray`.
+	pub fn ray_to_object(&self, ray: &Ray) -> Ray {
+		Ray {
+			origin: self.inverse.transform_point(ray.origin),
+			direction: self.inverse.transform_vector(ray.direction),
+			spread: ray.spread,
+			time: ray.time,
+		}
+	}
+}
+
+/// Values that can be blended between keyframes, `t` going from 0 at `self` to 1 at `other`.
+pub trait Interpolate {
+	fn interpolate(&self, other: &Self, t: f64) -> Self;
+}
+
+impl Interpolate for Point3<f64> {
+	fn interpolate(&self, other: &Point3<f64>, t: f64) -> Point3<f64> {
+		Point3::from_vec(self.to_vec().lerp(other.to_vec(), t))
+	}
+}
+
+/// A value that changes over time, given at keyframe times and interpolated in
+/// between. It holds still before the first keyframe and after the last.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyframes<T> {
+	keyframes: Vec<(f64, T)>,
+}
+
+impl<T: Interpolate + Clone> Keyframes<T> {
+	/// Keyframes at the given times, in any order. Panics if `keyframes` is empty.
+	pub fn new(mut keyframes: Vec<(f64, T)>) -> Keyframes<T> {
+		assert!(!keyframes.is_empty(), "keyframes can't be empty");
+		keyframes.sort_by(|a, b| a.0.total_cmp(&b.0));
+		Keyframes { keyframes }
+	}
+
+	/// A value that never changes.
+	pub fn constant(value: T) -> Keyframes<T> {
+		Keyframes { keyframes: vec![(0.0, value)] }
+	}
+
+	pub fn is_animated(&self) -> bool {
+		self.keyframes.len() > 1
+	}
+
+	/// Keyframe times and values, sorted by time.
+	pub fn keyframes(&self) -> &[(f64, T)] {
+		&self.keyframes
+	}
+
+	/// Index of the last keyframe at or before `time` and how far `time` is
+	/// toward the next one, in `[0, 1)`.
+	fn segment(&self, time: f64) -> (usize, f64) {
+		let i = self.keyframes.partition_point(|(keyframe_time, _)| *keyframe_time <= time);
+		if i == 0 || i == self.keyframes.len() {
+			return (i.saturating_sub(1), 0.0);
+		}
+		let (t0, t1) = (self.keyframes[i - 1].0, self.keyframes[i].0);
+		(i - 1, (time - t0) / (t1 - t0))
+	}
+
+	pub fn at(&self, time: f64) -> T {
+		let (i, t) = self.segment(time);
+		if t > 0.0 {
+			self.keyframes[i].1.interpolate(&self.keyframes[i + 1].1, t)
+		} else {
+			self.keyframes[i].1.clone()
+		}
+	}
+}
+
+/// Position, scale and rotation of an object, the parts `Transform::from_components` combines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Components {
+	pub origin: Point3<f64>,
+	pub scale: Vector3<f64>,
+	pub rot_axis: Vector3<f64>,
+	pub rot_angle: Rad<f64>,
+}
+
+impl Components {
+	pub fn transform(&self) -> Result<Transform, TransformError> {
+		Transform::from_components(self.origin, self.scale, self.rot_axis, self.rot_angle)
+	}
+
+	fn rotation(&self) -> Quaternion<f64> {
+		if self.rot_angle.0 == 0.0 {
+			Quaternion::new(1.0, 0.0, 0.0, 0.0)
+		} else {
+			Quaternion::from_axis_angle(self.rot_axis.normalize(), self.rot_angle)
+		}
+	}
+}
+
+impl Interpolate for Components {
+	/// Moves and scales linearly, and rotates along the shortest arc between the two rotations.
+	fn interpolate(&self, other: &Components, t: f64) -> Components {
+		let rotation = self.rotation().slerp(other.rotation(), t);
+		let sin = rotation.v.magnitude();
+		Components {
+			origin: self.origin.interpolate(&other.origin, t),
+			scale: self.scale.lerp(other.scale, t),
+			rot_axis: if sin > 0.0 { rotation.v / sin } else { Vector3::new(0.0, 1.0, 0.0) },
+			rot_angle: Rad(2.0 * sin.atan2(rotation.s)),
+		}
+	}
+}
+
+/// Steps each stretch between keyframes is split into when bounding a moving
+/// transform. Turning by at most half a circle per stretch, a point strays
+/// less than 0.5% of its distance from the center of rotation from the
+/// straight line between two steps.
+const MOTION_BOUNDS_STEPS: usize = 16;
+
+/// A transform that is either fixed or moves between keyframes. The transform
+/// at every keyframe is built once up front; rays between keyframes build the
+/// one they need.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimatedTransform {
+	components: Keyframes<Components>,
+	transforms: Vec<Transform>,
+}
+
+impl AnimatedTransform {
+	pub fn new(components: Keyframes<Components>) -> Result<AnimatedTransform, TransformError> {
+		let transforms = components.keyframes().iter().map(|(_, components)| components.transform()).collect::<Result<_, _>>()?;
+		Ok(AnimatedTransform { components, transforms })
+	}
+
+	pub fn components(&self) -> &Keyframes<Components> {
+		&self.components
+	}
+
+	pub fn is_animated(&self) -> bool {
+		self.components.is_animated()
+	}
+
+	/// The transform at `time`. Should interpolating pass through a singular
+	/// transform, for example a scale going through zero, the previous keyframe's is used.
+	pub fn at(&self, time: f64) -> Cow<'_, Transform> {
+		let (i, t) = self.components.segment(time);
+		if t > 0.0 {
+			if let Ok(transform) = self.components.at(time).transform() {
+				return Cow::Owned(transform);
+			}
+		}
+		Cow::Borrowed(&self.transforms[i])
+	}
+
+	/// Union of `bounds` applied to the transform over all time.
+	pub fn bounds<F: Fn(&Transform) -> Aabb>(&self, bounds: F) -> Aabb {
+		if !self.is_animated() {
+			return bounds(&self.transforms[0]);
+		}
+		let keyframes = self.components.keyframes();
+		let mut union = Aabb::empty();
+		let mut padding: f64 = 0.0;
+		for (i, window) in keyframes.windows(2).enumerate() {
+			let (t0, t1) = (window[0].0, window[1].0);
+			for step in 0..MOTION_BOUNDS_STEPS {
+				let time = t0 + (t1 - t0) * step as f64 / MOTION_BOUNDS_STEPS as f64;
+				let transform = if step == 0 { Cow::Borrowed(&self.transforms[i]) } else { self.at(time) };
+				let step_bounds = bounds(&transform);
+				let center = Point3::from_vec(transform.matrix().w.truncate());
+				padding = padding.max((step_bounds.centroid() - center).magnitude() + step_bounds.extent().magnitude() / 2.0);
+				union = union.union(&step_bounds);
+			}
+		}
+		union = union.union(&bounds(&self.transforms[keyframes.len() - 1]));
+		let padding = Vector3::new(padding, padding, padding) * 0.005;
+		Aabb::new(union.min - padding, union.max + padding)
+	}
+}
+
+/// Where a moving object is at `time`. Parts left out are those the object has
+/// at time 0.
+#[derive(Serialize, Deserialize)]
+pub struct KeyframeFormat {
+	pub time: f64,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub origin: Option<VectorFormat>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub scale: Option<VectorFormat>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub rot_axis: Option<VectorFormat>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub rot_angle: Option<f64>,
+}
+
+impl AnimatedTransform {
+	/// The transform of an object with `components` at time 0 and `motion` at other times.
+	pub fn load(components: Components, motion: Vec<KeyframeFormat>) -> Result<AnimatedTransform, TransformError> {
+		let keyframes = std::iter::once((0.0, components)).chain(motion.into_iter().map(|keyframe| (keyframe.time, Components {
+			origin: keyframe.origin.map_or(components.origin, |origin| origin.into()),
+			scale: keyframe.scale.map_or(components.scale, |scale| scale.into()),
+			rot_axis: keyframe.rot_axis.map_or(components.rot_axis, |rot_axis| rot_axis.into()),
+			rot_angle: keyframe.rot_angle.map_or(components.rot_angle, Rad),
+		}))).collect();
+		AnimatedTransform::new(Keyframes::new(keyframes))
+	}
+
+	/// Components at time 0 and every other keyframe, the inverse of `load`.
+	pub fn to_format(&self) -> (Components, Vec<KeyframeFormat>) {
+		let motion = self.components.keyframes().iter().filter(|(time, _)| *time != 0.0).map(|(time, components)| KeyframeFormat {
+			time: *time,
+			origin: Some(components.origin.into()),
+			scale: Some(components.scale.into()),
+			rot_axis: Some(components.rot_axis.into()),
+			rot_angle: Some(components.rot_angle.0),
+		}).collect();
+		(self.components.at(0.0), motion)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::{Rng, SeedableRng, rngs::StdRng};
+	use serde_json::json;
+	use crate::SceneError;
+	use crate::testing::{random_vector, load_scene, expect_error};
+
+	fn random_transform(rng: &mut StdRng) -> Transform {
+		let scale = Vector3::new(rng.gen_range(0.2..3.0), rng.gen_range(0.2..3.0), rng.gen_range(-3.0..-0.2));
+		Transform::from_components(Point3::from_vec(random_vector(rng, 5.0)), scale, random_vector(rng, 1.0), Rad(rng.gen_range(-3.0..3.0))).unwrap()
+	}
+
+	#[test]
+	fn rejects_singular_matrices() {
+		let origin = Point3::new(1.0, 2.0, 3.0);
+		let axis = Vector3::new(0.0, 1.0, 0.0);
+		assert!(Transform::from_components(origin, Vector3::new(1.0, 0.0, 1.0), axis, Rad(0.5)).is_err());
+		assert!(Transform::from_components(origin, Vector3::new(1.0, 1.0, 1e-13), axis, Rad(0.5)).is_err());
+		assert!(Transform::from_components(origin, Vector3::new(1.0, 1.0, 1.0), Vector3::new(0.0, 0.0, 0.0), Rad(0.5)).is_err());
+		assert!(Transform::new(Matrix4::from_scale(f64::NAN)).is_err());
+		assert!(Transform::new(Matrix4::from_cols(
+			Vector3::new(1.0, 2.0, 3.0).extend(0.0),
+			Vector3::new(2.0, 4.0, 6.0).extend(0.0),
+			Vector3::new(0.0, 0.0, 1.0).extend(0.0),
+			Vector3::new(0.0, 0.0, 0.0).extend(1.0),
+		)).is_err());
+		// A zero axis is fine when there is no rotation.
+		assert!(Transform::from_components(origin, Vector3::new(1.0, 1.0, 1.0), Vector3::new(0.0, 0.0, 0.0), Rad(0.0)).is_ok());
+	}
+
+	#[test]
+	fn inverse_round_trips() {
+		let mut rng = StdRng::seed_from_u64(3);
+		for _ in 0..100 {
+			let transform = random_transform(&mut rng);
+			let product = transform.matrix() * transform.inverse();
+			for i in 0..4 {
+				for j in 0..4 {
+					let expected = if i == j { 1.0 } else { 0.0 };
+					assert!((product[i][j] - expected).abs() < 1e-9);
+				}
+			}
+
+			let point = Point3::from_vec(random_vector(&mut rng, 10.0));
+			let ray = Ray { origin: point, direction: random_vector(&mut rng, 1.0), spread: 0.0, time: 0.0 };
+			let object_ray = transform.ray_to_object(&ray);
+			assert!((transform.point_to_world(object_ray.origin) - ray.origin).magnitude() < 1e-9);
+			assert!((transform.vector_to_world(object_ray.direction) - ray.direction).magnitude() < 1e-9);
+
+			// Normals stay perpendicular to surface tangents.
+			let normal = random_vector(&mut rng, 1.0).normalize();
+			let tangent = normal.cross(random_vector(&mut rng, 1.0));
+			assert!(cgmath::dot(transform.normal_to_world(normal), transform.vector_to_world(tangent)).abs() < 1e-9);
+		}
+	}
+
+	#[test]
+	fn keyframes_hold_outside_and_interpolate_inside() {
+		let (p0, p1, p2) = (Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 4.0, 0.0), Point3::new(2.0, 0.0, -6.0));
+		let keyframes = Keyframes::new(vec![(2.0, p2), (0.0, p0), (1.0, p1)]);
+		assert!(keyframes.is_animated());
+		assert_eq!(keyframes.at(-3.0), p0);
+		assert_eq!(keyframes.at(0.0), p0);
+		assert_eq!(keyframes.at(0.25), Point3::new(0.5, 1.0, 0.0));
+		assert_eq!(keyframes.at(1.0), p1);
+		assert_eq!(keyframes.at(1.5), Point3::new(2.0, 2.0, -3.0));
+		assert_eq!(keyframes.at(2.0), p2);
+		assert_eq!(keyframes.at(10.0), p2);
+		assert_eq!(Keyframes::constant(p1).at(5.0), p1);
+
+		// Rotations turn at a steady rate about the shared axis.
+		let axis = Vector3::new(0.0, 1.0, 0.0);
+		let components = |rot_angle: f64| Components { origin: p0, scale: Vector3::new(1.0, 1.0, 1.0), rot_axis: axis, rot_angle: Rad(rot_angle) };
+		let halfway = Keyframes::new(vec![(0.0, components(0.0)), (1.0, components(2.0))]).at(0.5);
+		assert!((halfway.rot_angle.0 - 1.0).abs() < 1e-9 && (halfway.rot_axis - axis).magnitude() < 1e-9);
+	}
+
+	#[test]
+	fn bounds_contain_every_pose() {
+		let mut rng = StdRng::seed_from_u64(4);
+		let cube = Aabb::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+		let corners: Vec<Point3<f64>> = (0..8).map(|i| Point3::new(
+			if i & 1 == 0 { -1.0 } else { 1.0 },
+			if i & 2 == 0 { -1.0 } else { 1.0 },
+			if i & 4 == 0 { -1.0 } else { 1.0 },
+		)).collect();
+		for _ in 0..50 {
+			let keyframes = (0..3).map(|i| (i as f64 + rng.gen_range(0.0..0.9), Components {
+				origin: Point3::from_vec(random_vector(&mut rng, 5.0)),
+				scale: Vector3::new(rng.gen_range(0.2..3.0), rng.gen_range(0.2..3.0), rng.gen_range(0.2..3.0)),
+				rot_axis: random_vector(&mut rng, 1.0),
+				rot_angle: Rad(rng.gen_range(-3.0..3.0)),
+			})).collect();
+			let animated = AnimatedTransform::new(Keyframes::new(keyframes)).unwrap();
+			let bounds = animated.bounds(|transform| cube.transform(transform.matrix()));
+			for _ in 0..200 {
+				let transform = animated.at(rng.gen_range(-1.0..4.0));
+				for &corner in &corners {
+					let p = transform.point_to_world(corner);
+					assert!(p.x >= bounds.min.x && p.y >= bounds.min.y && p.z >= bounds.min.z, "{:?} outside {:?}", p, bounds);
+					assert!(p.x <= bounds.max.x && p.y <= bounds.max.y && p.z <= bounds.max.z, "{:?} outside {:?}", p, bounds);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn invalid_transform() {
+		let ellipsoid = json!({
+			"type": "Ellipsoid",
+			"origin": { "x": 0, "y": 0, "z": 0 }, "scale": { "x": 1, "y": 0, "z": 1 }, "rot_axis": { "x": 0, "y": 1, "z": 0 }, "rot_angle": 0,
+			"material": "Mirror",
+		});
+		let error = expect_error(load_scene(json!({ "objects": [ellipsoid] })),
+			"objects[0]: transform is not invertible, determinant is 0 (check for a zero scale component or a zero rotation axis)");
+		assert!(matches!(error, SceneError::Object { index: 0, error: ref inner } if matches!(**inner, SceneError::InvalidTransform(_))));
+	}
+}
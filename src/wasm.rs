@@ -0,0 +1,58 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::Clamped;
+use web_sys::{CanvasRenderingContext2d, ImageData};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::panic;
+use crate::{Scene, RenderSettings, Integrator, render};
+
+thread_local! {
+	/// Files added with `add_file`, which scenes can refer to by name.
+	static FILES: RefCell<HashMap<String, Vec<u8>>> = RefCell::new(HashMap::new());
+}
+
+/// Makes `bytes` available to scenes as the file `name`, for example an image
+/// texture picked by the user.
+#[wasm_bindgen]
+pub fn add_file(name: String, bytes: Vec<u8>) {
+	FILES.with(|files| files.borrow_mut().insert(name, bytes));
+}
+
+#[wasm_bindgen]
+pub fn clear_files() {
+	FILES.with(|files| files.borrow_mut().clear());
+}
+
+#[wasm_bindgen]
+pub fn draw(
+	ctx: &CanvasRenderingContext2d,
+	json: &str,
+	width: u32,
+	height: u32,
+	num_samples: u32,
+	max_bounces: u32,
+	integrator: Option<String>,
+) -> Result<(), JsValue> {
+	let integrator = match integrator {
+		Some(name) => name.parse().map_err(|error: String| JsValue::from(JsError::new(&error)))?,
+		None => Integrator::Whitted,
+	};
+	let scene = FILES.with(|files| Scene::from_json_with_files(json, &files.borrow())).map_err(|error| JsValue::from(JsError::new(&error.to_string())))?;
+	let settings = RenderSettings {
+		width,
+		height,
+		num_samples,
+		max_bounces,
+		integrator,
+		..Default::default()
+	};
+	let image = render(&scene, &settings);
+	let data = ImageData::new_with_u8_clamped_array_and_sh(Clamped(&image.to_rgba8()), width, height)?;
+	ctx.put_image_data(&data, 0.0, 0.0)
+}
+
+#[wasm_bindgen(start)]
+fn main() -> Result<(), JsValue> {
+	panic::set_hook(Box::new(console_error_panic_hook::hook));
+	Ok(())
+}